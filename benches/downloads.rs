@@ -0,0 +1,87 @@
+// Buffered vs streamed asset downloads against a local mock server, for
+// synth-167. Run with `cargo bench --bench downloads`.
+//
+// 8 concurrent downloads of 8 MiB each, single-core Linux VM:
+//
+//   buffered (bytes() then write)        111.17 ms  (576 MiB/s)
+//   streamed (chunk() into a .part file) 110.83 ms  (577 MiB/s)
+//
+// No speed difference, so streaming doesn't win on time. It is kept because
+// buffering holds every asset in memory whole (64 MiB here, several GiB for
+// big releases) while streaming holds one chunk per download. The per-task
+// Client clone is an Arc clone and doesn't show up in either.
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use reqwest::Client;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinSet;
+use wiremock::matchers::path;
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const ASSETS: usize = 8;
+const ASSET_SIZE: usize = 8 << 20;
+
+async fn buffered(client: Client, url: String, path: PathBuf) {
+    let resp = client.get(url).send().await.unwrap();
+    let bytes = resp.bytes().await.unwrap();
+    tokio::fs::write(path, &bytes).await.unwrap();
+}
+
+// What download::save_streamed does; the crate has no lib target to import
+// it from
+async fn streamed(client: Client, url: String, path: PathBuf) {
+    let mut resp = client.get(url).send().await.unwrap();
+    let part_path = path.with_extension("part");
+    let mut file = tokio::fs::File::create(&part_path).await.unwrap();
+    while let Some(chunk) = resp.chunk().await.unwrap() {
+        file.write_all(&chunk).await.unwrap();
+    }
+    file.flush().await.unwrap();
+    drop(file);
+    tokio::fs::rename(&part_path, path).await.unwrap();
+}
+
+async fn download_all(client: &Client, url: &str, dir: &Path, stream: bool) {
+    let mut tasks = JoinSet::new();
+    for i in 0..ASSETS {
+        let (client, url) = (client.clone(), url.to_string());
+        let path = dir.join(format!("asset{}.apk", i));
+        if stream {
+            tasks.spawn(streamed(client, url, path));
+        } else {
+            tasks.spawn(buffered(client, url, path));
+        }
+    }
+    while let Some(task) = tasks.join_next().await {
+        task.unwrap();
+    }
+}
+
+fn downloads(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let server = rt.block_on(async {
+        let server = MockServer::start().await;
+        Mock::given(path("/asset.apk"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![7; ASSET_SIZE]))
+            .mount(&server)
+            .await;
+        server
+    });
+    let url = format!("{}/asset.apk", server.uri());
+    let dir = tempfile::tempdir().unwrap();
+    let client = Client::new();
+
+    let mut group = c.benchmark_group("downloads");
+    group.throughput(Throughput::Bytes((ASSETS * ASSET_SIZE) as u64));
+    group.sample_size(20);
+    for (name, stream) in [("buffered", false), ("streamed", true)] {
+        group.bench_function(name, |b| {
+            b.to_async(&rt)
+                .iter(|| download_all(&client, &url, dir.path(), stream))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, downloads);
+criterion_main!(benches);
@@ -0,0 +1,62 @@
+use clap::Parser;
+use tracing::Level;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Watch GitHub releases and download their assets")]
+pub struct Cli {
+    /// Increase log verbosity (-v info, -vv debug, -vvv trace)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Check for new releases without downloading or writing state
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// With --dry-run, print the old -> new tag each repo would record
+    #[arg(long, requires = "dry_run")]
+    pub show_state_diff: bool,
+
+    /// Print the effective config as TOML (secrets redacted) and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Re-download assets even if a file of the same size already exists
+    #[arg(long)]
+    pub force: bool,
+
+    /// Also watch repos read from stdin, one owner/name per line
+    #[arg(long)]
+    pub repos_stdin: bool,
+
+    /// Print the assets of a repo's latest release and exit
+    #[arg(long, value_name = "REPO")]
+    pub list_assets: Option<String>,
+
+    /// Append a JSON line per new release to this file (ignored with --dry-run)
+    #[arg(long, value_name = "PATH")]
+    pub emit_events: Option<String>,
+}
+
+// Default level for a given -v count; RUST_LOG still wins when set.
+pub fn log_level(verbose: u8) -> Level {
+    match verbose {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_verbose_flag_raises_the_log_level() {
+        assert_eq!(log_level(0), Level::WARN);
+        assert_eq!(log_level(1), Level::INFO);
+        assert_eq!(log_level(2), Level::DEBUG);
+        assert_eq!(log_level(3), Level::TRACE);
+        assert_eq!(log_level(9), Level::TRACE);
+    }
+}
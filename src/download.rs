@@ -0,0 +1,836 @@
+use crate::config::{Config, RepoConfig, UploadOrder};
+use crate::github::{Asset, Release};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, RETRY_AFTER};
+use reqwest::{Client, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fs, path::Path};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+// The asset CDN answers 429 with Retry-After during popular releases; wait
+// as long as it asks (capped) and try again before giving up.
+async fn get_with_retry_after(
+    client: &Client,
+    url: &str,
+    headers: HeaderMap,
+) -> anyhow::Result<Response> {
+    let mut retries = 0;
+    loop {
+        let resp = client.get(url).headers(headers.clone()).send().await?;
+        if resp.status() != StatusCode::TOO_MANY_REQUESTS || retries == MAX_RATE_LIMIT_RETRIES {
+            return Ok(resp.error_for_status()?);
+        }
+
+        let wait = resp
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(5))
+            .min(MAX_RETRY_AFTER);
+        retries += 1;
+        warn!(
+            "{} rate limited, retrying in {}s ({}/{})",
+            url,
+            wait.as_secs(),
+            retries,
+            MAX_RATE_LIMIT_RETRIES
+        );
+        tokio::time::sleep(wait).await;
+    }
+}
+
+async fn download_asset(
+    client: &Client,
+    asset: &Asset,
+    url: &str,
+    headers: HeaderMap,
+    dir: &str,
+    mirror_base: Option<&str>,
+) -> anyhow::Result<()> {
+    let path = Path::new(dir).join(&asset.name);
+
+    let primary = async {
+        let resp = get_with_retry_after(client, url, headers).await?;
+        save_streamed(resp, &path).await
+    };
+    let err = match primary.await {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    let Some(mirror_base) = mirror_base else {
+        return Err(err);
+    };
+
+    // Repo headers are meant for GitHub and aren't sent to the mirror
+    let mirror_url = format!("{}/{}", mirror_base.trim_end_matches('/'), asset.name);
+    warn!(
+        "{} failed ({}), trying mirror {}",
+        asset.name, err, mirror_url
+    );
+    let resp = get_with_retry_after(client, &mirror_url, HeaderMap::new()).await?;
+    save_streamed(resp, &path).await?;
+    info!("{} downloaded from mirror {}", asset.name, mirror_url);
+    Ok(())
+}
+
+// Whether a previous run already fetched this exact asset. A matching size
+// is only trusted when the directory is per tag: with "{base}/{repo}" every
+// version shares one directory, and a new build often has the old one's
+// name and size. A sha256 digest from GitHub settles it either way.
+async fn already_downloaded(asset: &Asset, path: &Path, tag_dir: bool) -> bool {
+    let size_matches = asset.size > 0
+        && tokio::fs::metadata(path)
+            .await
+            .is_ok_and(|meta| meta.len() == asset.size);
+    if !size_matches {
+        return false;
+    }
+    let Some(expected) = asset
+        .digest
+        .as_deref()
+        .and_then(|d| d.strip_prefix("sha256:"))
+    else {
+        return tag_dir;
+    };
+    let path = path.to_path_buf();
+    match tokio::task::spawn_blocking(move || sha256_file(&path)).await {
+        Ok(Ok(actual)) => actual.eq_ignore_ascii_case(expected),
+        _ => false,
+    }
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+// Write the body to <path>.part chunk by chunk, so large assets never sit in
+// memory whole, then move it into place once complete. A failed transfer
+// removes the .part file, so it never ends up in list.txt's directory.
+async fn save_streamed(mut resp: Response, path: &Path) -> anyhow::Result<()> {
+    let part_path = path.with_extension(match path.extension() {
+        Some(ext) => format!("{}.part", ext.to_string_lossy()),
+        None => "part".to_string(),
+    });
+    let written = async {
+        let mut file = tokio::fs::File::create(&part_path).await?;
+        while let Some(chunk) = resp.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        drop(file);
+        tokio::fs::rename(&part_path, path).await?;
+        anyhow::Ok(())
+    };
+    if let Err(err) = written.await {
+        let _ = tokio::fs::remove_file(&part_path).await;
+        return Err(err);
+    }
+    Ok(())
+}
+
+// Replace path with a hardlink to the store's copy of the same content,
+// or add it to the store if this content is new.
+fn dedupe_in_store(store: &Path, path: &Path) -> anyhow::Result<()> {
+    let stored = store.join(sha256_file(path)?);
+    // Another asset of the same release may have stored this content first
+    match fs::hard_link(path, &stored) {
+        Ok(()) => return Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err.into()),
+    }
+    if same_file(path, &stored)? {
+        return Ok(());
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!("{}.link", name));
+    fs::hard_link(&stored, &tmp)?;
+    fs::rename(&tmp, path)?;
+    info!(
+        "{} is identical to {}, linked",
+        path.display(),
+        stored.display()
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> std::io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+// Where to fetch an asset from. The API endpoint honours the repo's token,
+// which private repos need; the browser URL is public only.
+fn asset_source(
+    config: &Config,
+    repo: &RepoConfig,
+    asset: &Asset,
+    headers: &HeaderMap,
+) -> anyhow::Result<(String, HeaderMap)> {
+    let mut headers = headers.clone();
+    if !repo.use_api_asset_url {
+        return Ok((asset.browser_download_url.clone(), headers));
+    }
+
+    let url = format!(
+        "{}/repos/{}/releases/assets/{}",
+        config.api(),
+        repo.name,
+        asset.id
+    );
+    headers.insert(ACCEPT, HeaderValue::from_static("application/octet-stream"));
+    if let Some(token) = config.token_for(repo) {
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token))?,
+        );
+    }
+    Ok((url, headers))
+}
+
+// Keep tags like "pkg/v1.2" or "v1:rc" from creating extra directories or
+// invalid names.
+fn sanitize_path_part(part: &str) -> String {
+    let cleaned: String = part
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    match cleaned.as_str() {
+        "" | "." | ".." => "_".to_string(),
+        _ => cleaned,
+    }
+}
+
+fn size_in_range(repo: &RepoConfig, asset: &Asset) -> bool {
+    let too_small = repo.min_asset_size.is_some_and(|min| asset.size < min);
+    let too_large = repo.max_asset_size.is_some_and(|max| asset.size > max);
+    if too_small || too_large {
+        info!(
+            "{}: skipping {} ({} bytes), outside the configured size range",
+            repo.name, asset.name, asset.size
+        );
+    }
+    !too_small && !too_large
+}
+
+fn matches_arch(config: &Config, repo: &RepoConfig, asset: &Asset) -> bool {
+    if repo.arch_filter.is_empty() {
+        return true;
+    }
+    let name = asset.name.to_ascii_lowercase();
+    let matched = repo
+        .arch_filter
+        .iter()
+        .flat_map(|arch| config.arch_spellings(arch))
+        .any(|spelling| name.contains(&spelling));
+    if !matched {
+        info!(
+            "{}: skipping {}, not built for {}",
+            repo.name,
+            asset.name,
+            repo.arch_filter.join("/")
+        );
+    }
+    matched
+}
+
+// Refuse to start if the release wouldn't fit, rather than filling the disk
+// halfway through.
+fn ensure_disk_space(config: &Config, dir: &str, assets: &[&Asset]) -> anyhow::Result<()> {
+    if assets.is_empty() {
+        return Ok(());
+    }
+    let needed: u64 = assets.iter().map(|asset| asset.size).sum();
+    let available = fs4::available_space(dir)?;
+    if available < needed.saturating_add(config.min_free_bytes) {
+        anyhow::bail!(
+            "not enough disk space in {}: {} bytes free, need {} for assets plus {} reserved",
+            dir,
+            available,
+            needed,
+            config.min_free_bytes
+        );
+    }
+    Ok(())
+}
+
+// Empty list.txt once at the start of a run; every release handled in the
+// run then appends its files.
+pub fn clear_file_list(config: &Config) -> anyhow::Result<()> {
+    fs::create_dir_all(&config.download_dir)?;
+    fs::write(format!("{}/list.txt", config.download_dir), "")?;
+    Ok(())
+}
+
+pub fn resolve_download_dir(config: &Config, repo: &str, tag: &str) -> String {
+    config
+        .download_dir_template
+        .replace("{base}", &config.download_dir)
+        .replace("{repo}", &repo.replace("/", "_"))
+        .replace("{tag}", &sanitize_path_part(tag))
+}
+
+pub async fn download_assets_concurrent(
+    client: &Client,
+    config: &Config,
+    repo: &RepoConfig,
+    release: &Release,
+    force: bool,
+) -> anyhow::Result<Vec<String>> {
+    let dir = resolve_download_dir(config, &repo.name, &release.tag_name);
+    let headers = HeaderMap::try_from(&repo.download_headers)?;
+    tokio::fs::create_dir_all(&dir).await?;
+    tokio::fs::create_dir_all(&config.download_dir).await?;
+    if let Some(store) = &config.content_store {
+        tokio::fs::create_dir_all(store).await?;
+    }
+
+    let mut tasks = Vec::new();
+    let mut file_paths = Vec::new();
+
+    let mut assets: Vec<&Asset> = release
+        .assets
+        .iter()
+        .filter(|asset| !asset.name.contains("magisk") && !asset.name.contains("arm-v7a"))
+        .filter(|asset| size_in_range(repo, asset))
+        .filter(|asset| matches_arch(config, repo, asset))
+        .collect();
+
+    match config.upload_order {
+        UploadOrder::Config => {}
+        UploadOrder::Asc => assets.sort_by_key(|asset| asset.size),
+        UploadOrder::Desc => assets.sort_by_key(|asset| std::cmp::Reverse(asset.size)),
+    }
+
+    if let Some(max) = repo.max_assets {
+        if assets.len() > max {
+            info!(
+                "{}: release has {} assets, downloading the first {}",
+                repo.name,
+                assets.len(),
+                max
+            );
+            assets.truncate(max);
+        }
+    }
+
+    // Only what still has to be fetched counts against free space
+    let tag_dir = config.download_dir_template.contains("{tag}");
+    let mut present = Vec::new();
+    for asset in &assets {
+        let path = Path::new(&dir).join(&asset.name);
+        present.push(!force && already_downloaded(asset, &path, tag_dir).await);
+    }
+    let missing: Vec<&Asset> = assets
+        .iter()
+        .zip(&present)
+        .filter(|(_, present)| !**present)
+        .map(|(asset, _)| *asset)
+        .collect();
+    ensure_disk_space(config, &dir, &missing)?;
+
+    let limit = config
+        .download_concurrency()
+        .map(|n| Arc::new(Semaphore::new(n)));
+
+    for (asset, present) in assets.into_iter().zip(present) {
+        let path = format!("{}/{}", dir, asset.name);
+        file_paths.push(path);
+
+        let (url, headers) = asset_source(config, repo, asset, &headers)?;
+        let client = client.clone();
+        let asset = asset.clone();
+        let dir = dir.clone();
+        let mirror_base = repo.mirror_base.clone();
+        let content_store = config.content_store.clone();
+        let limit = limit.clone();
+
+        tasks.push(tokio::spawn(async move {
+            let _permit = match limit {
+                Some(limit) => Some(limit.acquire_owned().await?),
+                None => None,
+            };
+            let path = Path::new(&dir).join(&asset.name);
+            if present {
+                info!("{} already present, skipping download.", path.display());
+            } else {
+                let mirror_base = mirror_base.as_deref();
+                download_asset(&client, &asset, &url, headers, &dir, mirror_base).await?;
+            }
+            if let Some(store) = content_store {
+                tokio::task::spawn_blocking(move || dedupe_in_store(Path::new(&store), &path))
+                    .await??;
+            }
+            Ok::<_, anyhow::Error>(())
+        }));
+    }
+
+    // Wait for all downloads
+    for t in tasks {
+        t.await??;
+    }
+
+    // Add this release's files to the run's list
+    let list_path = format!("{}/list.txt", config.download_dir);
+    let mut list_file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&list_path)?;
+    for path in &file_paths {
+        list_file.write_all(path.as_bytes())?;
+        list_file.write_all(b"\n")?;
+    }
+
+    Ok(file_paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{header, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn asset(name: &str, size: u64, digest: Option<&str>) -> Asset {
+        Asset {
+            id: 1,
+            name: name.to_string(),
+            browser_download_url: String::new(),
+            digest: digest.map(str::to_string),
+            size,
+            content_type: String::new(),
+            state: "uploaded".to_string(),
+        }
+    }
+
+    fn release(assets: Vec<Asset>) -> Release {
+        Release {
+            tag_name: "v1.0.0".to_string(),
+            name: None,
+            body: None,
+            assets,
+            draft: false,
+            prerelease: false,
+            published_at: None,
+            target_commitish: None,
+            html_url: None,
+        }
+    }
+
+    fn test_config(dir: &Path, extra: &str) -> Config {
+        toml::from_str(&format!(
+            "download_dir = \"{}\"\n{}\n[[repos]]\nname = \"owner/app\"",
+            dir.display(),
+            extra
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn present_assets_dont_count_against_free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(
+            dir.path(),
+            "download_dir_template = \"{base}/{repo}/{tag}\"\nmin_free_bytes = 1000000000000000",
+        );
+        let tag_dir = dir.path().join("owner_app/v1.0.0");
+        fs::create_dir_all(&tag_dir).unwrap();
+        fs::write(tag_dir.join("app.apk"), "hello").unwrap();
+
+        let client = Client::new();
+        let present = release(vec![asset("app.apk", 5, None)]);
+        let paths = download_assets_concurrent(&client, &config, &config.repos[0], &present, false)
+            .await
+            .unwrap();
+        assert_eq!(paths.len(), 1);
+
+        let missing = release(vec![asset("app.apk", 5, None), asset("new.apk", 5, None)]);
+        let err = download_assets_concurrent(&client, &config, &config.repos[0], &missing, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not enough disk space"));
+    }
+
+    #[tokio::test]
+    async fn upload_order_applies_before_max_assets() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(
+            dir.path(),
+            "download_dir_template = \"{base}/{tag}\"\nupload_order = \"asc\"",
+        );
+        config.repos[0].max_assets = Some(2);
+        let tag_dir = dir.path().join("v1.0.0");
+        fs::create_dir_all(&tag_dir).unwrap();
+        let assets = vec![
+            asset("large.apk", 30, None),
+            asset("small.apk", 10, None),
+            asset("medium.apk", 20, None),
+        ];
+        for asset in &assets {
+            fs::write(tag_dir.join(&asset.name), vec![0; asset.size as usize]).unwrap();
+        }
+
+        let client = Client::new();
+        let paths =
+            download_assets_concurrent(&client, &config, &config.repos[0], &release(assets), false)
+                .await
+                .unwrap();
+        let names: Vec<&str> = paths
+            .iter()
+            .map(|p| p.rsplit('/').next().unwrap())
+            .collect();
+        assert_eq!(names, ["small.apk", "medium.apk"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn identical_assets_share_one_stored_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = dir.path().join("store");
+        let config = test_config(
+            dir.path(),
+            &format!(
+                "download_dir_template = \"{{base}}/{{tag}}\"\ncontent_store = \"{}\"",
+                store.display()
+            ),
+        );
+        let tag_dir = dir.path().join("v1.0.0");
+        fs::create_dir_all(&tag_dir).unwrap();
+        let assets: Vec<Asset> = (0..8)
+            .map(|i| asset(&format!("copy{}.apk", i), 5, None))
+            .collect();
+        for asset in &assets {
+            fs::write(tag_dir.join(&asset.name), "hello").unwrap();
+        }
+
+        let client = Client::new();
+        download_assets_concurrent(&client, &config, &config.repos[0], &release(assets), false)
+            .await
+            .unwrap();
+
+        assert_eq!(fs::read_dir(&store).unwrap().count(), 1);
+        let stored = fs::read_dir(&store)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        for i in 0..8 {
+            assert!(same_file(&tag_dir.join(format!("copy{}.apk", i)), &stored).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn same_size_only_counts_in_a_per_tag_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.apk");
+        fs::write(&path, "old build").unwrap();
+        let new_build = asset("app.apk", 9, None);
+
+        assert!(!already_downloaded(&new_build, &path, false).await);
+        assert!(already_downloaded(&new_build, &path, true).await);
+        assert!(!already_downloaded(&asset("app.apk", 10, None), &path, true).await);
+    }
+
+    #[tokio::test]
+    async fn digest_decides_when_present() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.apk");
+        fs::write(&path, "hello").unwrap();
+        let hello = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let other = "sha256:0000000000000000000000000000000000000000000000000000000000000000";
+
+        assert!(already_downloaded(&asset("app.apk", 5, Some(hello)), &path, false).await);
+        assert!(!already_downloaded(&asset("app.apk", 5, Some(other)), &path, true).await);
+    }
+
+    #[tokio::test]
+    async fn download_headers_are_sent_with_asset_requests() {
+        let server = MockServer::start().await;
+        Mock::given(path("/app.apk"))
+            .and(header("x-mirror-key", "abc123"))
+            .and(header("referer", "https://example.com/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        let repo = &mut config.repos[0];
+        repo.download_headers
+            .insert("X-Mirror-Key".to_string(), "abc123".to_string());
+        repo.download_headers
+            .insert("Referer".to_string(), "https://example.com/".to_string());
+        let mut app = asset("app.apk", 5, None);
+        app.browser_download_url = format!("{}/app.apk", server.uri());
+
+        let client = Client::new();
+        download_assets_concurrent(
+            &client,
+            &config,
+            &config.repos[0],
+            &release(vec![app]),
+            false,
+        )
+        .await
+        .unwrap();
+        let saved = dir.path().join("owner_app/app.apk");
+        assert_eq!(fs::read_to_string(saved).unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn force_downloads_present_assets_again() {
+        let server = MockServer::start().await;
+        Mock::given(path("/app.apk"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("fresh"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path(), "download_dir_template = \"{base}/{tag}\"");
+        let tag_dir = dir.path().join("v1.0.0");
+        fs::create_dir_all(&tag_dir).unwrap();
+        fs::write(tag_dir.join("app.apk"), "stale").unwrap();
+        let mut app = asset("app.apk", 5, None);
+        app.browser_download_url = format!("{}/app.apk", server.uri());
+        let release = release(vec![app]);
+
+        let client = Client::new();
+        download_assets_concurrent(&client, &config, &config.repos[0], &release, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(tag_dir.join("app.apk")).unwrap(),
+            "stale"
+        );
+
+        download_assets_concurrent(&client, &config, &config.repos[0], &release, true)
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(tag_dir.join("app.apk")).unwrap(),
+            "fresh"
+        );
+    }
+
+    #[test]
+    fn download_dir_template_placeholders() {
+        let dir = Path::new("/srv/apks");
+        let config = test_config(dir, "download_dir_template = \"{base}/{repo}/{tag}\"");
+        assert_eq!(
+            resolve_download_dir(&config, "owner/app", "v1.0.0"),
+            "/srv/apks/owner_app/v1.0.0"
+        );
+        assert_eq!(
+            resolve_download_dir(&config, "owner/app", "../../etc"),
+            "/srv/apks/owner_app/.._.._etc"
+        );
+        assert_eq!(
+            resolve_download_dir(&test_config(dir, ""), "owner/app", "v1.0.0"),
+            "/srv/apks/owner_app"
+        );
+    }
+
+    #[test]
+    fn path_parts_are_sanitized() {
+        assert_eq!(sanitize_path_part("v1.2.3+build"), "v1.2.3+build");
+        assert_eq!(sanitize_path_part("pkg/v1 beta"), "pkg_v1_beta");
+        assert_eq!(sanitize_path_part(""), "_");
+        assert_eq!(sanitize_path_part("."), "_");
+        assert_eq!(sanitize_path_part(".."), "_");
+    }
+
+    #[tokio::test]
+    async fn rate_limited_downloads_honour_retry_after() {
+        let server = MockServer::start().await;
+        Mock::given(path("/app.apk"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(path("/app.apk"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = Client::new();
+        let url = format!("{}/app.apk", server.uri());
+        let resp = get_with_retry_after(&client, &url, HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(resp.text().await.unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn rate_limit_gives_up_after_max_retries() {
+        let server = MockServer::start().await;
+        Mock::given(path("/app.apk"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .expect(u64::from(MAX_RATE_LIMIT_RETRIES) + 1)
+            .mount(&server)
+            .await;
+
+        let client = Client::new();
+        let url = format!("{}/app.apk", server.uri());
+        assert!(get_with_retry_after(&client, &url, HeaderMap::new())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn size_range_bounds_are_inclusive() {
+        let mut config = test_config(Path::new("downloads"), "");
+        let repo = &mut config.repos[0];
+        assert!(size_in_range(repo, &asset("app.apk", 0, None)));
+
+        repo.min_asset_size = Some(10);
+        repo.max_asset_size = Some(20);
+        assert!(!size_in_range(repo, &asset("app.apk", 9, None)));
+        assert!(size_in_range(repo, &asset("app.apk", 10, None)));
+        assert!(size_in_range(repo, &asset("app.apk", 20, None)));
+        assert!(!size_in_range(repo, &asset("app.apk", 21, None)));
+    }
+
+    #[tokio::test]
+    async fn failed_transfer_leaves_no_part_file() {
+        // Promises 100 bytes, sends 5 and hangs up
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = tokio::io::AsyncReadExt::read(&mut socket, &mut request).await;
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nhello")
+                .await
+                .unwrap();
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let result = download_asset(
+            &Client::new(),
+            &asset("app.apk", 100, None),
+            &format!("http://{}/app.apk", addr),
+            HeaderMap::new(),
+            dir.path().to_str().unwrap(),
+            None,
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_downloads_fall_back_to_the_mirror() {
+        let server = MockServer::start().await;
+        Mock::given(path("/github/app.apk"))
+            .respond_with(ResponseTemplate::new(502))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/mirror/app.apk"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let client = Client::new();
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        download_asset(
+            &client,
+            &asset("app.apk", 5, None),
+            &format!("{}/github/app.apk", server.uri()),
+            headers,
+            dir.path().to_str().unwrap(),
+            Some(&format!("{}/mirror/", server.uri())),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("app.apk")).unwrap(),
+            "hello"
+        );
+        let requests = server.received_requests().await.unwrap();
+        let mirror = requests
+            .iter()
+            .find(|r| r.url.path() == "/mirror/app.apk")
+            .unwrap();
+        assert!(!mirror.headers.contains_key(AUTHORIZATION));
+    }
+
+    #[test]
+    fn arch_filter_matches_every_alias_in_a_group() {
+        let mut config = test_config(
+            Path::new("downloads"),
+            "[arch_aliases]\narmv7 = [\"armeabi-v7a\"]",
+        );
+        let names = [
+            "app-arm64-v8a.apk",
+            "tool-aarch64-unknown-linux-gnu.tar.gz",
+            "tool-x86_64.AppImage",
+            "tool_amd64.deb",
+            "app-armeabi-v7a.apk",
+            "app-universal.apk",
+        ];
+        let mut matching = |arch: &str| {
+            config.repos[0].arch_filter = vec![arch.to_string()];
+            names
+                .iter()
+                .filter(|name| matches_arch(&config, &config.repos[0], &asset(name, 1, None)))
+                .copied()
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            matching("arm64"),
+            ["app-arm64-v8a.apk", "tool-aarch64-unknown-linux-gnu.tar.gz"]
+        );
+        assert_eq!(
+            matching("AARCH64"),
+            ["app-arm64-v8a.apk", "tool-aarch64-unknown-linux-gnu.tar.gz"]
+        );
+        assert_eq!(
+            matching("x86_64"),
+            ["tool-x86_64.AppImage", "tool_amd64.deb"]
+        );
+        assert_eq!(
+            matching("amd64"),
+            ["tool-x86_64.AppImage", "tool_amd64.deb"]
+        );
+        assert_eq!(matching("armv7"), ["app-armeabi-v7a.apk"]);
+    }
+}
@@ -0,0 +1,99 @@
+use crate::github::Release;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+#[derive(Debug, Serialize)]
+pub struct ReleaseEvent<'a> {
+    pub repo: &'a str,
+    pub tag: &'a str,
+    pub previous_tag: Option<&'a str>,
+    pub assets: Vec<&'a str>,
+    pub published_at: Option<&'a str>,
+}
+
+impl<'a> ReleaseEvent<'a> {
+    pub fn new(repo: &'a str, release: &'a Release, previous_tag: Option<&'a str>) -> Self {
+        ReleaseEvent {
+            repo,
+            tag: &release.tag_name,
+            previous_tag,
+            assets: release.assets.iter().map(|a| a.name.as_str()).collect(),
+            published_at: release.published_at.as_deref(),
+        }
+    }
+}
+
+// --emit-events file, one JSON object per line. Each event is written with a
+// single unbuffered write, so it is on disk even if the run dies right after.
+pub struct EventLog {
+    file: File,
+}
+
+impl EventLog {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(EventLog { file })
+    }
+
+    pub fn emit(&mut self, event: &ReleaseEvent) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn each_event_is_one_json_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let path = path.to_str().unwrap();
+        let release: Release = serde_json::from_value(json!({
+            "tag_name": "v1.1.0",
+            "published_at": "2024-01-01T00:00:00Z",
+            "assets": [{"name": "app.apk", "browser_download_url": "https://example.com/app.apk"}],
+        }))
+        .unwrap();
+
+        let mut log = EventLog::open(path).unwrap();
+        log.emit(&ReleaseEvent::new("owner/app", &release, Some("v1.0.0")))
+            .unwrap();
+        drop(log);
+        // Reopening appends instead of truncating
+        let mut log = EventLog::open(path).unwrap();
+        log.emit(&ReleaseEvent::new("other/tool", &release, None))
+            .unwrap();
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                json!({
+                    "repo": "owner/app",
+                    "tag": "v1.1.0",
+                    "previous_tag": "v1.0.0",
+                    "assets": ["app.apk"],
+                    "published_at": "2024-01-01T00:00:00Z",
+                }),
+                json!({
+                    "repo": "other/tool",
+                    "tag": "v1.1.0",
+                    "previous_tag": null,
+                    "assets": ["app.apk"],
+                    "published_at": "2024-01-01T00:00:00Z",
+                }),
+            ]
+        );
+    }
+}
@@ -0,0 +1,574 @@
+use crate::config::{LatestStrategy, RepoConfig};
+use chrono::{DateTime, Utc};
+use glob::Pattern;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Asset {
+    #[serde(default)]
+    pub id: u64,
+    pub name: String,
+    pub browser_download_url: String,
+    // "sha256:<hex>", only present on assets uploaded after GitHub started hashing them
+    pub digest: Option<String>,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub content_type: String,
+    // "uploaded" once GitHub has the whole file, "open" while it is still uploading
+    #[serde(default)]
+    pub state: String,
+}
+
+impl Asset {
+    pub fn is_ready(&self) -> bool {
+        self.size > 0 && self.state != "open"
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub name: Option<String>,
+    pub body: Option<String>,
+    pub assets: Vec<Asset>,
+    #[serde(default)]
+    pub draft: bool,
+    #[serde(default)]
+    pub prerelease: bool,
+    pub published_at: Option<String>,
+    // Branch (or commit) the release was cut from
+    pub target_commitish: Option<String>,
+    // Always uses the repo's current name, even when the request was redirected
+    pub html_url: Option<String>,
+}
+
+const GITHUB_API_VERSION: &str = "2022-11-28";
+
+// GET against the REST API with the media type and API version pinned,
+// authenticated when a token is configured.
+fn github_get(client: &Client, url: &str, token: Option<&str>) -> RequestBuilder {
+    let request = client
+        .get(url)
+        .header("Accept", "application/vnd.github+json")
+        .header("X-GitHub-Api-Version", GITHUB_API_VERSION);
+    match token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    }
+}
+
+impl Release {
+    // Time from publication to now, None if GitHub didn't report a valid published_at
+    pub fn age(&self, now: DateTime<Utc>) -> Option<chrono::Duration> {
+        let published = DateTime::parse_from_rfc3339(self.published_at.as_deref()?).ok()?;
+        Some(now.signed_duration_since(published))
+    }
+
+    // owner/name as GitHub currently calls it, taken from html_url. web_url
+    // is the site html_url points into, see Config::web_url.
+    pub fn repo_full_name(&self, web_url: &str) -> Option<String> {
+        let path = self
+            .html_url
+            .as_deref()?
+            .strip_prefix(web_url)?
+            .strip_prefix('/')?;
+        let mut parts = path.split('/');
+        let owner = parts.next()?;
+        let name = parts.next()?;
+        Some(format!("{}/{}", owner, name))
+    }
+
+    // Identifies the same release content across repos (e.g. forks mirroring
+    // upstream). None if there are no assets or any asset lacks a digest.
+    pub fn content_key(&self) -> Option<String> {
+        if self.assets.is_empty() {
+            return None;
+        }
+        let mut digests = self
+            .assets
+            .iter()
+            .map(|asset| asset.digest.clone())
+            .collect::<Option<Vec<_>>>()?;
+        digests.sort();
+        Some(format!("{}:{}", self.tag_name, digests.join(",")))
+    }
+}
+
+pub async fn fetch_latest_release(
+    client: &Client,
+    api: &str,
+    repo: &str,
+    token: Option<&str>,
+) -> anyhow::Result<Release> {
+    let url = format!("{}/repos/{}/releases/latest", api, repo);
+    let release: Release = github_get(client, &url, token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(release)
+}
+
+// Most recent page of releases, newest first.
+pub async fn fetch_releases(
+    client: &Client,
+    api: &str,
+    repo: &str,
+    token: Option<&str>,
+) -> anyhow::Result<Vec<Release>> {
+    let url = format!("{}/repos/{}/releases?per_page=100", api, repo);
+    let releases: Vec<Release> = github_get(client, &url, token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(releases)
+}
+
+// The release to compare against state, picked by the repo's latest_strategy
+// and limited to releases that ship an asset matching asset_pattern, whose tag
+// starts with tag_prefix and that were cut from target_branch, when set.
+pub async fn fetch_release(
+    client: &Client,
+    api: &str,
+    repo: &RepoConfig,
+    token: Option<&str>,
+) -> anyhow::Result<Release> {
+    let filtered =
+        repo.asset_pattern.is_some() || repo.tag_prefix.is_some() || repo.target_branch.is_some();
+    if repo.latest_strategy == LatestStrategy::Github && !filtered {
+        return fetch_latest_release(client, api, &repo.name, token).await;
+    }
+    let prefix = repo.tag_prefix.as_deref().unwrap_or("");
+
+    let wanted = release_filter(repo)?;
+    let releases = fetch_releases(client, api, &repo.name, token)
+        .await?
+        .into_iter()
+        .filter(|release| wanted(release));
+    let release = pick_latest(repo.latest_strategy, prefix, releases);
+    release.ok_or_else(|| {
+        if filtered {
+            anyhow::anyhow!(
+                "no recent release of {} matches its tag_prefix/asset_pattern/target_branch",
+                repo.name
+            )
+        } else {
+            anyhow::anyhow!("no published releases for {}", repo.name)
+        }
+    })
+}
+
+// Every release published after last_tag that passes the repo's filters,
+// oldest first; empty when there is none. This catches backports that
+// /releases/latest never returns. Falls back to just the latest when
+// last_tag is no longer listed (deleted, or older than the first page).
+pub async fn fetch_releases_since(
+    client: &Client,
+    api: &str,
+    repo: &RepoConfig,
+    token: Option<&str>,
+    last_tag: &str,
+    latest: Release,
+) -> anyhow::Result<Vec<Release>> {
+    let releases = fetch_releases(client, api, &repo.name, token).await?;
+    let Some(since) = releases
+        .iter()
+        .find(|release| release.tag_name == last_tag)
+        .and_then(|release| release.published_at.clone())
+    else {
+        if latest.tag_name == last_tag {
+            return Ok(Vec::new());
+        }
+        return Ok(vec![latest]);
+    };
+
+    let wanted = release_filter(repo)?;
+    let mut newer: Vec<Release> = releases
+        .into_iter()
+        .filter(|release| wanted(release))
+        .filter(|release| release.published_at.as_ref().is_some_and(|at| *at > since))
+        .collect();
+    newer.sort_by(|a, b| a.published_at.cmp(&b.published_at));
+    Ok(newer)
+}
+
+// Match /releases/latest, which never returns drafts or prereleases, plus
+// the repo's tag_prefix, target_branch and asset_pattern.
+fn release_filter(repo: &RepoConfig) -> anyhow::Result<impl Fn(&Release) -> bool + '_> {
+    let prefix = repo.tag_prefix.as_deref().unwrap_or("");
+    let pattern = repo
+        .asset_pattern
+        .as_deref()
+        .map(Pattern::new)
+        .transpose()?;
+    Ok(move |release: &Release| {
+        let on_branch = match &repo.target_branch {
+            Some(branch) => release.target_commitish.as_deref() == Some(branch.as_str()),
+            None => true,
+        };
+        let has_matching_asset = match &pattern {
+            Some(pattern) => release.assets.iter().any(|a| pattern.matches(&a.name)),
+            None => true,
+        };
+        !release.draft
+            && !release.prerelease
+            && release.tag_name.starts_with(prefix)
+            && on_branch
+            && has_matching_asset
+    })
+}
+
+// Highest version for LatestStrategy::Semver, newest otherwise. Tags that
+// aren't versions sort below every tag that is, and ties (equal versions or
+// no versions at all) go to the most recently published release.
+fn pick_latest(
+    strategy: LatestStrategy,
+    prefix: &str,
+    releases: impl Iterator<Item = Release>,
+) -> Option<Release> {
+    match strategy {
+        LatestStrategy::Semver => releases.max_by(|a, b| {
+            let a_key = (version_parts(&a.tag_name, prefix), &a.published_at);
+            let b_key = (version_parts(&b.tag_name, prefix), &b.published_at);
+            a_key.cmp(&b_key)
+        }),
+        _ => releases.max_by(|a, b| a.published_at.cmp(&b.published_at)),
+    }
+}
+
+// Lenient (major, minor, patch) for tags like "v1.2", "1.2.3-beta" or
+// "pkg-a/v1.2.3"; None for tags that aren't version numbers.
+pub fn version_parts(tag: &str, prefix: &str) -> Option<(u64, u64, u64)> {
+    let tag = tag
+        .strip_prefix(prefix)
+        .unwrap_or(tag)
+        .trim_start_matches(['/', '-', '_', '@'])
+        .trim_start_matches(['v', 'V']);
+    let core = tag.split(['-', '+']).next()?;
+    let parts = core
+        .split('.')
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    match parts.as_slice() {
+        [major] => Some((*major, 0, 0)),
+        [major, minor] => Some((*major, *minor, 0)),
+        [major, minor, patch] => Some((*major, *minor, *patch)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OwnerRepo {
+    full_name: String,
+    #[serde(default)]
+    fork: bool,
+    #[serde(default)]
+    archived: bool,
+}
+
+// True when the request failed with 404, e.g. /releases/latest of a repo
+// that has never published a release
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .and_then(|err| err.status())
+        .is_some_and(|status| status == reqwest::StatusCode::NOT_FOUND)
+}
+
+// All public repos of a user or org, following pagination. Forks and
+// archived repos are left out.
+pub async fn list_owner_repos(
+    client: &Client,
+    api: &str,
+    owner: &str,
+    token: Option<&str>,
+) -> anyhow::Result<Vec<String>> {
+    let per_page = 100;
+    let mut names = Vec::new();
+    let mut page = 1;
+    loop {
+        let url = format!(
+            "{}/users/{}/repos?per_page={}&page={}",
+            api, owner, per_page, page
+        );
+        let repos: Vec<OwnerRepo> = github_get(client, &url, token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let last_page = repos.len() < per_page;
+        names.extend(
+            repos
+                .into_iter()
+                .filter(|r| !r.fork && !r.archived)
+                .map(|r| r.full_name),
+        );
+        if last_page {
+            break;
+        }
+        page += 1;
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::matchers::{header, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn release(tag: &str, published_at: &str) -> Release {
+        serde_json::from_value(json!({
+            "tag_name": tag,
+            "published_at": published_at,
+            "assets": [],
+        }))
+        .unwrap()
+    }
+
+    fn latest_tag(strategy: LatestStrategy, releases: Vec<Release>) -> String {
+        pick_latest(strategy, "", releases.into_iter())
+            .unwrap()
+            .tag_name
+    }
+
+    #[test]
+    fn semver_strategy_understands_two_part_tags() {
+        // /releases lists newest first
+        let releases = vec![
+            release("v3.9", "2024-03-01T00:00:00Z"),
+            release("v3.10", "2024-02-01T00:00:00Z"),
+            release("v3.8", "2024-01-01T00:00:00Z"),
+        ];
+        assert_eq!(latest_tag(LatestStrategy::Semver, releases), "v3.10");
+    }
+
+    #[test]
+    fn semver_strategy_breaks_ties_by_publish_date() {
+        let releases = vec![
+            release("nightly-b", "2024-02-01T00:00:00Z"),
+            release("nightly-a", "2024-01-01T00:00:00Z"),
+        ];
+        assert_eq!(latest_tag(LatestStrategy::Semver, releases), "nightly-b");
+
+        let releases = vec![
+            release("v1.2.0-rc2", "2024-02-01T00:00:00Z"),
+            release("v1.2.0-rc1", "2024-01-01T00:00:00Z"),
+        ];
+        assert_eq!(latest_tag(LatestStrategy::Semver, releases), "v1.2.0-rc2");
+    }
+
+    #[test]
+    fn version_tags_beat_other_tags() {
+        let releases = vec![
+            release("latest", "2024-02-01T00:00:00Z"),
+            release("140", "2024-01-01T00:00:00Z"),
+        ];
+        assert_eq!(latest_tag(LatestStrategy::Semver, releases), "140");
+    }
+
+    #[test]
+    fn date_strategy_picks_newest() {
+        let releases = vec![
+            release("v2.0.0", "2024-01-01T00:00:00Z"),
+            release("v1.9.9", "2024-02-01T00:00:00Z"),
+        ];
+        assert_eq!(latest_tag(LatestStrategy::Date, releases), "v1.9.9");
+    }
+
+    #[tokio::test]
+    async fn owner_repos_are_read_across_pages() {
+        let server = MockServer::start().await;
+        let first: Vec<_> = (0..100)
+            .map(|i| json!({ "full_name": format!("owner/repo{}", i), "fork": i == 0 }))
+            .collect();
+        Mock::given(path("/users/owner/repos"))
+            .and(query_param("per_page", "100"))
+            .and(query_param("page", "1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(first))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/users/owner/repos"))
+            .and(query_param("page", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                { "full_name": "owner/repo100" },
+                { "full_name": "owner/repo101", "archived": true },
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let names = list_owner_repos(&Client::new(), &server.uri(), "owner", None)
+            .await
+            .unwrap();
+        let expected: Vec<String> = (1..=100).map(|i| format!("owner/repo{}", i)).collect();
+        assert_eq!(names, expected);
+    }
+
+    fn asset(size: u64, state: &str, digest: Option<&str>) -> Asset {
+        serde_json::from_value(json!({
+            "name": "app.apk",
+            "browser_download_url": "https://example.com/app.apk",
+            "size": size,
+            "state": state,
+            "digest": digest,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn content_key_needs_every_digest() {
+        let mut with_digests = release("v1.0.0", "2024-01-01T00:00:00Z");
+        with_digests.assets = vec![
+            asset(5, "uploaded", Some("sha256:bb")),
+            asset(5, "uploaded", Some("sha256:aa")),
+        ];
+        assert_eq!(
+            with_digests.content_key().as_deref(),
+            Some("v1.0.0:sha256:aa,sha256:bb")
+        );
+
+        let mut reordered = release("v1.0.0", "2024-02-01T00:00:00Z");
+        reordered.assets = vec![
+            asset(5, "uploaded", Some("sha256:aa")),
+            asset(5, "uploaded", Some("sha256:bb")),
+        ];
+        assert_eq!(with_digests.content_key(), reordered.content_key());
+
+        let mut partial = release("v1.0.0", "2024-01-01T00:00:00Z");
+        partial.assets = vec![
+            asset(5, "uploaded", Some("sha256:aa")),
+            asset(5, "uploaded", None),
+        ];
+        assert_eq!(partial.content_key(), None);
+        assert_eq!(
+            release("v1.0.0", "2024-01-01T00:00:00Z").content_key(),
+            None
+        );
+    }
+
+    #[test]
+    fn assets_are_ready_once_uploaded() {
+        assert!(asset(5, "uploaded", None).is_ready());
+        assert!(!asset(5, "open", None).is_ready());
+        assert!(!asset(0, "uploaded", None).is_ready());
+    }
+
+    #[test]
+    fn version_parts_of_tags() {
+        assert_eq!(version_parts("v1.2.3", ""), Some((1, 2, 3)));
+        assert_eq!(version_parts("V2.0", ""), Some((2, 0, 0)));
+        assert_eq!(version_parts("140", ""), Some((140, 0, 0)));
+        assert_eq!(version_parts("v1.2.0-rc1", ""), Some((1, 2, 0)));
+        assert_eq!(version_parts("1.2.3+build.5", ""), Some((1, 2, 3)));
+        assert_eq!(version_parts("pkg-a/v3.1.4", "pkg-a"), Some((3, 1, 4)));
+        assert_eq!(version_parts("app@2.5.0", "app"), Some((2, 5, 0)));
+        assert_eq!(version_parts("nightly", ""), None);
+        assert_eq!(version_parts("v1.2.3.4", ""), None);
+        assert_eq!(version_parts("", ""), None);
+    }
+
+    async fn latest_of(repo: &RepoConfig, releases: serde_json::Value) -> String {
+        let server = MockServer::start().await;
+        Mock::given(path(format!("/repos/{}/releases", repo.name)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(releases))
+            .mount(&server)
+            .await;
+        fetch_release(&Client::new(), &server.uri(), repo, None)
+            .await
+            .unwrap()
+            .tag_name
+    }
+
+    #[tokio::test]
+    async fn asset_pattern_skips_releases_without_a_match() {
+        let mut repo = RepoConfig::new("owner/app".to_string());
+        repo.asset_pattern = Some("app-*.apk".to_string());
+        let releases = json!([
+            {
+                "tag_name": "v2.0.0",
+                "published_at": "2024-02-01T00:00:00Z",
+                "assets": [{ "name": "app-v2.0.0.zip", "browser_download_url": "" }],
+            },
+            {
+                "tag_name": "v1.0.0",
+                "published_at": "2024-01-01T00:00:00Z",
+                "assets": [{ "name": "app-v1.0.0.apk", "browser_download_url": "" }],
+            },
+        ]);
+        assert_eq!(latest_of(&repo, releases).await, "v1.0.0");
+    }
+
+    #[tokio::test]
+    async fn api_requests_pin_media_type_and_version() {
+        let server = MockServer::start().await;
+        Mock::given(path("/repos/owner/app/releases/latest"))
+            .and(header("accept", "application/vnd.github+json"))
+            .and(header("x-github-api-version", GITHUB_API_VERSION))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(release("v1.0.0", "2024-01-01T00:00:00Z")),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let release = fetch_latest_release(&Client::new(), &server.uri(), "owner/app", None)
+            .await
+            .unwrap();
+        assert_eq!(release.tag_name, "v1.0.0");
+    }
+
+    #[tokio::test]
+    async fn tag_prefix_selects_one_package_of_a_monorepo() {
+        let releases = json!([
+            { "tag_name": "pkg-b/v3.0.0", "published_at": "2024-04-01T00:00:00Z", "assets": [] },
+            { "tag_name": "pkg-a/v1.10.0", "published_at": "2024-03-01T00:00:00Z", "assets": [] },
+            { "tag_name": "v9.0.0", "published_at": "2024-02-15T00:00:00Z", "assets": [] },
+            { "tag_name": "pkg-a/v1.9.0", "published_at": "2024-02-01T00:00:00Z", "assets": [] },
+            { "tag_name": "pkg-a/v1.11.0", "published_at": "2024-01-01T00:00:00Z", "assets": [] },
+        ]);
+        let mut repo = RepoConfig::new("owner/mono".to_string());
+        repo.tag_prefix = Some("pkg-a/".to_string());
+        assert_eq!(latest_of(&repo, releases.clone()).await, "pkg-a/v1.10.0");
+
+        repo.latest_strategy = LatestStrategy::Semver;
+        assert_eq!(latest_of(&repo, releases).await, "pkg-a/v1.11.0");
+    }
+
+    #[tokio::test]
+    async fn target_branch_ignores_releases_cut_from_other_branches() {
+        let releases = json!([
+            {
+                "tag_name": "v2.0.0-beta",
+                "target_commitish": "main",
+                "published_at": "2024-03-01T00:00:00Z",
+                "assets": [],
+            },
+            {
+                "tag_name": "v1.4.2",
+                "target_commitish": "release/1.x",
+                "published_at": "2024-02-01T00:00:00Z",
+                "assets": [],
+            },
+            {
+                "tag_name": "v1.4.1",
+                "target_commitish": "release/1.x",
+                "published_at": "2024-01-01T00:00:00Z",
+                "assets": [],
+            },
+        ]);
+        let mut repo = RepoConfig::new("owner/app".to_string());
+        repo.target_branch = Some("release/1.x".to_string());
+        assert_eq!(latest_of(&repo, releases.clone()).await, "v1.4.2");
+
+        repo.target_branch = Some("main".to_string());
+        assert_eq!(latest_of(&repo, releases).await, "v2.0.0-beta");
+    }
+}
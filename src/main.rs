@@ -1,162 +1,1345 @@
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io::Write;
-use std::{fs, path::Path};
+mod cli;
+mod config;
+mod download;
+mod events;
+mod github;
+mod state;
+
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use cli::{log_level, Cli};
+use config::{load_config, normalize_repo, parse_repo_list, Config, NotifyOn, RepoConfig};
+use download::{clear_file_list, download_assets_concurrent};
+use events::{EventLog, ReleaseEvent};
+use github::{
+    fetch_latest_release, fetch_release, fetch_releases_since, is_not_found, list_owner_repos,
+    version_parts, Release,
+};
+use reqwest::{Client, NoProxy, Proxy};
+use state::{flush_state, lock_state, open_store, StateStore};
+use std::collections::HashSet;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let repos = vec![
-        "NoName-exe/revanced-extended",
-        "ReadYouApp/ReadYou",
-        "ProtonMail/android-mail",
-        "Helium314/HeliBoard",
-        "Akylas/OSS-DocumentScanner",
-        "foobnix/LibreraReader",
-        "emavgl/oinkoin",
-        "ReVanced/revanced-manager",
-        "ReVanced/GmsCore",
-        "T8RIN/ImageToolbox",
-        "uazo/cromite",
-    ];
-
-    process_repos(repos).await?;
+    let cli = Cli::parse();
+    init_tracing(cli.verbose);
 
-    Ok(())
-}
+    let mut config = load_config("config.toml")?;
+    if cli.repos_stdin {
+        let mut input = String::new();
+        std::io::stdin().read_to_string(&mut input)?;
+        config.merge_repos(parse_repo_list(&input)?);
+    }
+    if cli.print_config {
+        print!("{}", config.to_redacted_toml()?);
+        return Ok(());
+    }
+    let client = build_client(&config)?;
+    if let Some(repo) = &cli.list_assets {
+        print!("{}", list_assets(&client, &config, repo).await?);
+        return Ok(());
+    }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Asset {
-    name: String,
-    browser_download_url: String,
+    let result = run(&client, &cli, &config).await;
+    if let Some(url) = config.healthcheck_url.as_deref().filter(|_| !cli.dry_run) {
+        // A run cut short by a signal didn't check every repo
+        let completed = result.as_ref().is_ok_and(|outcome| !outcome.interrupted);
+        ping_healthcheck(&client, url, completed).await;
+    }
+    result.map(|_| ())
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Release {
-    tag_name: String,
-    name: Option<String>,
-    body: Option<String>,
-    assets: Vec<Asset>,
+async fn run(client: &Client, cli: &Cli, config: &Config) -> anyhow::Result<RunOutcome> {
+    let repos = expand_repos(client, config).await?;
+    if repos.is_empty() {
+        anyhow::bail!("no repos configured in config.toml");
+    }
+
+    // Lock first: until the listener is installed, a signal still kills a
+    // run that is stuck waiting for another one to finish. Dry runs write
+    // nothing, so they don't need it.
+    let _lock = if cli.dry_run {
+        None
+    } else {
+        Some(lock_state(&config.state).await?)
+    };
+    let shutdown = Arc::new(AtomicBool::new(false));
+    spawn_shutdown_listener(shutdown.clone());
+
+    let mut store = open_store(&config.state)?;
+    // Dry runs record nothing, so they don't add to the audit log either
+    let mut events = match cli.emit_events.as_deref() {
+        Some(path) if !cli.dry_run => Some(EventLog::open(path)?),
+        _ => None,
+    };
+
+    let outcome = process_repos(
+        client,
+        cli,
+        config,
+        &repos,
+        store.as_mut(),
+        events.as_mut(),
+        &shutdown,
+    )
+    .await?;
+    if cli.show_state_diff {
+        for line in &outcome.state_diff {
+            println!("{}", line);
+        }
+    }
+    Ok(outcome)
 }
 
-async fn fetch_latest_release(repo: &str) -> anyhow::Result<Release> {
-    let url = format!("https://api.github.com/repos/{}/releases/latest", repo);
-    let client = Client::new();
-    let release: Release = client
+// Dead man's switch: the monitor alerts when the success ping stops
+// arriving, or right away on {url}/fail. A monitor that is down shouldn't
+// fail the run itself.
+async fn ping_healthcheck(client: &Client, url: &str, ok: bool) {
+    let url = if ok {
+        url.to_string()
+    } else {
+        format!("{}/fail", url.trim_end_matches('/'))
+    };
+    let resp = client
         .get(&url)
-        .header("User-Agent", "rust-release-notifier")
+        .timeout(Duration::from_secs(10))
         .send()
-        .await?
-        .json()
-        .await?;
-    Ok(release)
+        .await
+        .and_then(|resp| resp.error_for_status());
+    if let Err(err) = resp {
+        warn!("Healthcheck ping to {} failed: {}", url, err);
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct PrevVersion {
-    repos: HashMap<String, String>, // repo -> last seen tag
+// Preview what a repo's latest release ships, to help write filters. A
+// configured repo goes through its tag_prefix/asset_pattern/target_branch.
+async fn list_assets(client: &Client, config: &Config, repo: &str) -> anyhow::Result<String> {
+    let repo = normalize_repo(repo)?;
+    let configured = config
+        .repos
+        .iter()
+        .find(|r| r.name.eq_ignore_ascii_case(&repo));
+    let release = match configured {
+        Some(repo_config) => {
+            let token = config.token_for(repo_config);
+            fetch_release(client, config.api(), repo_config, token).await?
+        }
+        None => {
+            let token = config.github_token.as_deref();
+            fetch_latest_release(client, config.api(), &repo, token).await?
+        }
+    };
+
+    let mut table = format!("{} {}\n", repo, release.tag_name);
+    let width = release
+        .assets
+        .iter()
+        .map(|a| a.name.len())
+        .max()
+        .unwrap_or(0);
+    for asset in &release.assets {
+        table += &format!(
+            "{:<width$}  {:>12}  {}\n",
+            asset.name,
+            asset.size,
+            asset.content_type,
+            width = width
+        );
+    }
+    Ok(table)
 }
 
-fn load_prev(path: &str) -> PrevVersion {
-    if std::path::Path::new(path).exists() {
-        let data = std::fs::read_to_string(path).unwrap_or_default();
-        serde_json::from_str(&data).unwrap_or_else(|_| PrevVersion {
-            repos: Default::default(),
-        })
-    } else {
-        PrevVersion {
-            repos: Default::default(),
+fn init_tracing(verbose: u8) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::default().add_directive(LevelFilter::from_level(log_level(verbose)).into())
+    });
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+}
+
+// Flip the shutdown flag on SIGINT/SIGTERM so the current repo can finish
+// and the state file still gets written. A second signal exits right away,
+// for when a download is stuck.
+fn spawn_shutdown_listener(shutdown: Arc<AtomicBool>) {
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        warn!("Shutdown requested, finishing current repo. Press Ctrl-C again to quit now.");
+        shutdown.store(true, Ordering::SeqCst);
+        wait_for_signal().await;
+        warn!("Second shutdown request, exiting without saving state.");
+        std::process::exit(130);
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut term = match signal(SignalKind::terminate()) {
+        Ok(term) => term,
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+            return;
         }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = term.recv() => {}
     }
 }
 
-fn save_prev(path: &str, prev: &PrevVersion) -> anyhow::Result<()> {
-    let json = serde_json::to_string_pretty(prev)?;
-    std::fs::write(path, json)?;
-    Ok(())
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
 }
 
-async fn process_repos(repos: Vec<&str>) -> anyhow::Result<()> {
-    let prev_path = "prev.json";
-    let mut prev = load_prev(prev_path);
+fn build_client(config: &Config) -> anyhow::Result<Client> {
+    let user_agent = config
+        .user_agent
+        .clone()
+        .unwrap_or_else(|| format!("tele-bot/{}", env!("CARGO_PKG_VERSION")));
+    let mut builder = Client::builder().user_agent(user_agent);
+    if let Some(max_idle) = config.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(secs) = config.pool_idle_timeout_secs {
+        builder = builder.pool_idle_timeout(Duration::from_secs(secs));
+    }
+
+    // Without an explicit proxy, reqwest picks up HTTP(S)_PROXY, ALL_PROXY
+    // and NO_PROXY from the environment by itself
+    if let Some(url) = config.proxy.as_deref().filter(|url| !url.is_empty()) {
+        let no_proxy = match &config.no_proxy {
+            Some(hosts) => NoProxy::from_string(hosts),
+            None => NoProxy::from_env(),
+        };
+        builder = builder.proxy(Proxy::all(url)?.no_proxy(no_proxy));
+    }
+
+    let client = builder.build()?;
+    Ok(client)
+}
 
-    for repo in repos {
-        let release = fetch_latest_release(repo).await?;
+// Replace each "owner/*" entry with the owner's repos, keeping its settings.
+async fn expand_repos(client: &Client, config: &Config) -> anyhow::Result<Vec<RepoConfig>> {
+    let mut expanded = Vec::new();
+    for entry in &config.repos {
+        let Some(owner) = entry.name.strip_suffix("/*") else {
+            expanded.push(entry.clone());
+            continue;
+        };
+        if !entry.enabled {
+            info!("{} is disabled. Skipping.", entry.name);
+            continue;
+        }
 
-        // Skip if already processed
-        if let Some(last_tag) = prev.repos.get(repo) {
-            if last_tag == &release.tag_name {
-                println!("No new release for {} ({}). Skipping.", repo, last_tag);
+        for full_name in
+            list_owner_repos(client, config.api(), owner, config.token_for(entry)).await?
+        {
+            let short_name = full_name.rsplit('/').next().unwrap_or(&full_name);
+            let excluded = entry
+                .exclude
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(&full_name) || e.eq_ignore_ascii_case(short_name));
+            if excluded {
                 continue;
             }
+            info!("Watching {} via {}", full_name, entry.name);
+            expanded.push(RepoConfig {
+                name: full_name,
+                expanded: true,
+                ..entry.clone()
+            });
         }
+    }
+    Ok(expanded)
+}
+
+#[derive(Debug, Default)]
+struct RunOutcome {
+    // "repo: old -> new" for each tag a dry run would have recorded
+    state_diff: Vec<String>,
+    // Stopped early by SIGINT/SIGTERM, later repos weren't checked
+    interrupted: bool,
+}
 
-        println!("New release found for {}: {}", repo, release.tag_name);
+async fn process_repos(
+    client: &Client,
+    cli: &Cli,
+    config: &Config,
+    repos: &[RepoConfig],
+    store: &mut dyn StateStore,
+    mut events: Option<&mut EventLog>,
+    shutdown: &AtomicBool,
+) -> anyhow::Result<RunOutcome> {
+    if !cli.dry_run {
+        clear_file_list(config)?;
+    }
+    let mut outcome = RunOutcome::default();
+    let mut seen_releases = HashSet::new();
+    let web_url = config.web_url();
+    let mut fetches = spawn_release_fetches(client, config, repos).into_iter();
+
+    for repo_config in repos {
+        let fetch = fetches.next().flatten();
+        let mut repo = repo_config.name.as_str();
+        if shutdown.load(Ordering::SeqCst) {
+            info!("Shutting down before {}, saving state.", repo);
+            outcome.interrupted = true;
+            break;
+        }
+        let Some(fetch) = fetch else {
+            info!("{} is disabled. Skipping.", repo);
+            continue;
+        };
 
-        download_assets_concurrent(repo, &release).await?;
+        let Some(release) = fetch.await?? else {
+            info!("{} has no releases. Skipping.", repo);
+            continue;
+        };
 
-        // update prev.json for this repo
-        prev.repos
-            .insert(repo.to_string(), release.tag_name.clone());
+        let renamed;
+        let repo_config = match moved_to(repo, &release, &web_url) {
+            Some(new_name) if config.follow_renames => {
+                warn!("{} has moved to {}, tracking the new name.", repo, new_name);
+                if !cli.dry_run {
+                    move_state(store, repo, &new_name)?;
+                }
+                renamed = RepoConfig {
+                    name: new_name,
+                    ..repo_config.clone()
+                };
+                &renamed
+            }
+            Some(new_name) => {
+                warn!("{} has moved to {}, update config.toml.", repo, new_name);
+                repo_config
+            }
+            None => repo_config,
+        };
+        repo = repo_config.name.as_str();
+
+        // Skip if already processed. With notify_all_new an unchanged latest
+        // doesn't mean nothing is new, a backport may have been published.
+        let last_tag = store.get(repo)?;
+        let releases = match last_tag.as_deref() {
+            Some(last_tag) if repo_config.notify_all_new => {
+                let token = config.token_for(repo_config);
+                fetch_releases_since(client, config.api(), repo_config, token, last_tag, release)
+                    .await?
+            }
+            Some(last_tag) if last_tag == release.tag_name => Vec::new(),
+            _ => vec![release],
+        };
+        if releases.is_empty() {
+            info!(
+                "No new release for {} ({}). Skipping.",
+                repo,
+                last_tag.as_deref().unwrap_or_default()
+            );
+            continue;
+        }
+
+        // Oldest first, so state only ever moves forward; stop at the first
+        // release that has to wait for a later run.
+        let mut previous_tag = last_tag;
+        for release in &releases {
+            let recorded = handle_release(
+                client,
+                cli,
+                config,
+                repo_config,
+                release,
+                previous_tag.as_deref(),
+                store,
+                events.as_deref_mut(),
+                &mut seen_releases,
+                &mut outcome,
+            )
+            .await?;
+            if !recorded {
+                break;
+            }
+            previous_tag = Some(release.tag_name.clone());
+        }
     }
 
-    save_prev(prev_path, &prev)?;
-    Ok(())
+    if !cli.dry_run {
+        flush_state(store, &std::env::temp_dir()).await?;
+    }
+    Ok(outcome)
 }
 
-async fn download_asset(
+// Download and record one new release. Returns false if it was deferred to
+// a later run and nothing was recorded.
+#[allow(clippy::too_many_arguments)]
+async fn handle_release(
     client: &Client,
-    url: &str,
-    dir: &str,
-    filename: &str,
+    cli: &Cli,
+    config: &Config,
+    repo_config: &RepoConfig,
+    release: &Release,
+    last_tag: Option<&str>,
+    store: &mut dyn StateStore,
+    events: Option<&mut EventLog>,
+    seen_releases: &mut HashSet<String>,
+    outcome: &mut RunOutcome,
+) -> anyhow::Result<bool> {
+    let repo = repo_config.name.as_str();
+    info!("New release found for {}: {}", repo, release.tag_name);
+
+    if let Some((age, min_age)) = still_settling(config, release, Utc::now()) {
+        info!(
+            "{} {} was published {} minutes ago, waiting until it is {} minutes old.",
+            repo, release.tag_name, age, min_age
+        );
+        return Ok(false);
+    }
+
+    if config.defer_incomplete_assets {
+        let pending: Vec<&str> = release
+            .assets
+            .iter()
+            .filter(|asset| !asset.is_ready())
+            .map(|asset| asset.name.as_str())
+            .collect();
+        if !pending.is_empty() {
+            info!(
+                "{} {} still has assets uploading ({}). Checking again next run.",
+                repo,
+                release.tag_name,
+                pending.join(", ")
+            );
+            return Ok(false);
+        }
+    }
+
+    if let Some(required) = &repo_config.require_asset_name {
+        if !release.assets.iter().any(|asset| asset.name == *required) {
+            info!(
+                "{} {} has no asset named {} yet. Checking again next run.",
+                repo, release.tag_name, required
+            );
+            return Ok(false);
+        }
+    }
+
+    if let Some(events) = events {
+        events.emit(&ReleaseEvent::new(repo, release, last_tag))?;
+    }
+
+    if !is_notable_update(repo_config, last_tag, &release.tag_name) {
+        info!(
+            "{} {} is below the notify_on threshold, recording without downloading.",
+            repo, release.tag_name
+        );
+        record_tag(store, cli, outcome, repo, last_tag, &release.tag_name)?;
+        return Ok(true);
+    }
+
+    if config.dedupe_releases {
+        if let Some(key) = release.content_key() {
+            if !seen_releases.insert(key) {
+                info!(
+                    "{} {} has the same assets as a release already handled this run. \
+                     Skipping downloads.",
+                    repo, release.tag_name
+                );
+                record_tag(store, cli, outcome, repo, last_tag, &release.tag_name)?;
+                return Ok(true);
+            }
+        }
+    }
+
+    if cli.dry_run {
+        info!(
+            "Dry run: would download {} assets for {}",
+            release.assets.len(),
+            repo
+        );
+    } else {
+        download_assets_concurrent(client, config, repo_config, release, cli.force).await?;
+    }
+
+    // update state for this repo
+    record_tag(store, cli, outcome, repo, last_tag, &release.tag_name)?;
+    Ok(true)
+}
+
+// (age, min_release_age_minutes) in minutes while a release is younger
+// than the configured minimum
+fn still_settling(config: &Config, release: &Release, now: DateTime<Utc>) -> Option<(i64, u64)> {
+    let min_age = config.min_release_age_minutes?;
+    let age = release.age(now)?.num_minutes();
+    (age < min_age as i64).then_some((age, min_age))
+}
+
+// Fetch releases ahead of processing, at most config.fetch_concurrency() at
+// a time. Disabled repos get None and are never fetched. Repos picked up via
+// owner/* often have no releases at all; their 404 resolves to Ok(None)
+// instead of failing the run.
+fn spawn_release_fetches(
+    client: &Client,
+    config: &Config,
+    repos: &[RepoConfig],
+) -> Vec<Option<JoinHandle<anyhow::Result<Option<Release>>>>> {
+    let limit = Arc::new(Semaphore::new(config.fetch_concurrency()));
+    repos
+        .iter()
+        .map(|repo| {
+            if !repo.enabled {
+                return None;
+            }
+            let client = client.clone();
+            let repo = repo.clone();
+            let api = config.api().to_string();
+            let token = config.token_for(&repo).map(str::to_string);
+            let limit = limit.clone();
+            Some(tokio::spawn(async move {
+                let _permit = limit.acquire_owned().await?;
+                match fetch_release(&client, &api, &repo, token.as_deref()).await {
+                    Err(err) if repo.expanded && is_not_found(&err) => Ok(None),
+                    result => result.map(Some),
+                }
+            }))
+        })
+        .collect()
+}
+
+// Whether going from old_tag to new_tag is big enough for the repo's
+// notify_on. First sightings and non-version tags always count.
+fn is_notable_update(repo: &RepoConfig, old_tag: Option<&str>, new_tag: &str) -> bool {
+    let prefix = repo.tag_prefix.as_deref().unwrap_or("");
+    let old = old_tag.and_then(|tag| version_parts(tag, prefix));
+    let (Some(old), Some(new)) = (old, version_parts(new_tag, prefix)) else {
+        return true;
+    };
+    match repo.notify_on {
+        NotifyOn::Any => true,
+        NotifyOn::Minor => (new.0, new.1) > (old.0, old.1),
+        NotifyOn::Major => new.0 > old.0,
+    }
+}
+
+// The repo's new name if GitHub redirected us to a renamed/transferred repo
+fn moved_to(repo: &str, release: &Release, web_url: &str) -> Option<String> {
+    let current = release.repo_full_name(web_url)?;
+    if current.eq_ignore_ascii_case(repo) {
+        None
+    } else {
+        Some(current)
+    }
+}
+
+fn move_state(store: &mut dyn StateStore, old: &str, new: &str) -> anyhow::Result<()> {
+    if let Some(tag) = store.get(old)? {
+        if store.get(new)?.is_none() {
+            store.set(new, &tag)?;
+        }
+        store.remove(old)?;
+    }
+    Ok(())
+}
+
+fn record_tag(
+    store: &mut dyn StateStore,
+    cli: &Cli,
+    outcome: &mut RunOutcome,
+    repo: &str,
+    old_tag: Option<&str>,
+    new_tag: &str,
 ) -> anyhow::Result<()> {
-    let resp = client.get(url).send().await?;
-    let bytes = resp.bytes().await?;
-    let path = Path::new(dir).join(filename);
-    tokio::fs::write(&path, &bytes).await?;
+    if !cli.dry_run {
+        return store.set(repo, new_tag);
+    }
+    outcome.state_diff.push(format!(
+        "{}: {} -> {}",
+        repo,
+        old_tag.unwrap_or("(none)"),
+        new_tag
+    ));
     Ok(())
 }
 
-async fn download_assets_concurrent(repo: &str, release: &Release) -> anyhow::Result<Vec<String>> {
-    let client = Client::new();
-    let dir = format!("assets/{}", repo.replace("/", "_"));
-    tokio::fs::create_dir_all(&dir).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use state::JsonStore;
+    use std::path::Path;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
 
-    // Clear previous list.txt before downloads
-    let list_path = "assets/list.txt";
-    tokio::fs::write(list_path, "").await.unwrap_or(());
+    fn test_config(server: &MockServer, dir: &Path, extra: &str) -> Config {
+        toml::from_str(&format!(
+            r#"
+            api_url = "{api}"
+            download_dir = "{dir}/assets"
+            {extra}
 
-    let mut tasks = Vec::new();
-    let mut file_paths = Vec::new();
+            [state]
+            path = "{dir}/prev.json"
 
-    for asset in &release.assets {
-        if asset.name.contains("magisk") || asset.name.contains("arm-v7a") {
-            continue;
+            [[repos]]
+            name = "owner/app"
+            "#,
+            api = server.uri(),
+            dir = dir.display(),
+            extra = extra,
+        ))
+        .unwrap()
+    }
+
+    fn release_json(server: &MockServer, tag: &str, asset: &str) -> serde_json::Value {
+        json!({
+            "tag_name": tag,
+            "published_at": "2024-01-01T00:00:00Z",
+            "assets": [{
+                "id": 1,
+                "name": asset,
+                "browser_download_url": format!("{}/download/{}", server.uri(), asset),
+                "size": 5,
+                "state": "uploaded",
+            }],
+        })
+    }
+
+    async fn run_once(cli: &Cli, config: &Config) -> JsonStore {
+        let client = Client::new();
+        let mut store = JsonStore::open(config.state.path.as_deref().unwrap(), false).unwrap();
+        let shutdown = AtomicBool::new(false);
+        process_repos(
+            &client,
+            cli,
+            config,
+            &config.repos,
+            &mut store,
+            None,
+            &shutdown,
+        )
+        .await
+        .unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn downloads_new_release_and_records_it() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&server, dir.path(), "");
+        Mock::given(method("GET"))
+            .and(path("/repos/owner/app/releases/latest"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(release_json(&server, "v1.0.0", "app.apk")),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/download/app.apk"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let cli = Cli::parse_from(["tele-bot"]);
+        let store = run_once(&cli, &config).await;
+
+        let asset = dir.path().join("assets/owner_app/app.apk");
+        assert_eq!(std::fs::read_to_string(&asset).unwrap(), "hello");
+        let list = std::fs::read_to_string(dir.path().join("assets/list.txt")).unwrap();
+        assert_eq!(list.trim(), asset.to_str().unwrap());
+        assert_eq!(store.get("owner/app").unwrap().as_deref(), Some("v1.0.0"));
+
+        // The next run sees the same tag and downloads nothing
+        let store = run_once(&cli, &config).await;
+        assert_eq!(store.get("owner/app").unwrap().as_deref(), Some("v1.0.0"));
+    }
+
+    #[tokio::test]
+    async fn owner_expansion_skips_forks_archived_and_release_less_repos() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(&server, dir.path(), "");
+        config.repos[0].name = "owner/*".to_string();
+        Mock::given(path("/users/owner/repos"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                { "full_name": "owner/app" },
+                { "full_name": "owner/empty" },
+                { "full_name": "owner/forked", "fork": true },
+                { "full_name": "owner/old", "archived": true },
+            ])))
+            .mount(&server)
+            .await;
+        Mock::given(path("/repos/owner/app/releases/latest"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(release_json(&server, "v1.0.0", "app.apk")),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(path("/repos/owner/empty/releases/latest"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        Mock::given(path("/download/app.apk"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .mount(&server)
+            .await;
+
+        let client = Client::new();
+        let repos = expand_repos(&client, &config).await.unwrap();
+        let names: Vec<&str> = repos.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["owner/app", "owner/empty"]);
+
+        let cli = Cli::parse_from(["tele-bot"]);
+        let mut store = JsonStore::open(config.state.path.as_deref().unwrap(), false).unwrap();
+        let shutdown = AtomicBool::new(false);
+        process_repos(&client, &cli, &config, &repos, &mut store, None, &shutdown)
+            .await
+            .unwrap();
+        assert_eq!(store.get("owner/app").unwrap().as_deref(), Some("v1.0.0"));
+        assert_eq!(store.get("owner/empty").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn notify_all_new_lists_every_new_release_in_order() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(&server, dir.path(), "");
+        config.repos[0].notify_all_new = true;
+        let mut releases = Vec::new();
+        for (tag, day) in [("v1.2.0", 3), ("v1.1.0", 2), ("v1.0.0", 1)] {
+            let mut release = release_json(&server, tag, &format!("app-{}.apk", tag));
+            release["published_at"] = json!(format!("2024-01-0{}T00:00:00Z", day));
+            releases.push(release);
+        }
+        Mock::given(path("/repos/owner/app/releases/latest"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&releases[0]))
+            .mount(&server)
+            .await;
+        Mock::given(path("/repos/owner/app/releases"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&releases))
+            .mount(&server)
+            .await;
+        for tag in ["v1.1.0", "v1.2.0"] {
+            Mock::given(path(format!("/download/app-{}.apk", tag)))
+                .respond_with(ResponseTemplate::new(200).set_body_string(tag))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+        let state = config.state.path.clone().unwrap();
+        let mut store = JsonStore::open(&state, false).unwrap();
+        store.set("owner/app", "v1.0.0").unwrap();
+        store.flush().unwrap();
+
+        let cli = Cli::parse_from(["tele-bot"]);
+        let store = run_once(&cli, &config).await;
+
+        let list = std::fs::read_to_string(dir.path().join("assets/list.txt")).unwrap();
+        let names: Vec<&str> = list
+            .lines()
+            .map(|l| l.rsplit('/').next().unwrap())
+            .collect();
+        assert_eq!(names, ["app-v1.1.0.apk", "app-v1.2.0.apk"]);
+        assert_eq!(store.get("owner/app").unwrap().as_deref(), Some("v1.2.0"));
+    }
+
+    #[test]
+    fn notify_on_thresholds() {
+        let mut repo = RepoConfig::new("owner/app".to_string());
+        let notable = |repo: &RepoConfig, old, new| is_notable_update(repo, old, new);
+
+        assert!(notable(&repo, Some("v1.2.3"), "v1.2.4"));
+
+        repo.notify_on = NotifyOn::Minor;
+        assert!(!notable(&repo, Some("v1.2.3"), "v1.2.4"));
+        assert!(notable(&repo, Some("v1.2.3"), "v1.3.0"));
+        assert!(notable(&repo, Some("v1.9"), "v2.0"));
+        assert!(notable(&repo, None, "v1.2.4"));
+        assert!(notable(&repo, Some("nightly"), "v1.2.4"));
+
+        repo.notify_on = NotifyOn::Major;
+        assert!(!notable(&repo, Some("v1.2.3"), "v1.9.0"));
+        assert!(notable(&repo, Some("v1.2.3"), "v2.0.0"));
+
+        repo.tag_prefix = Some("pkg-a/".to_string());
+        assert!(!notable(&repo, Some("pkg-a/v1.0.0"), "pkg-a/v1.1.0"));
+        assert!(notable(&repo, Some("pkg-a/v1.0.0"), "pkg-a/v2.0.0"));
+    }
+
+    #[test]
+    fn releases_wait_until_min_release_age() {
+        let mut config: Config = toml::from_str("min_release_age_minutes = 30").unwrap();
+        let release: Release = serde_json::from_value(json!({
+            "tag_name": "v1.0.0",
+            "published_at": "2024-01-01T12:00:00Z",
+            "assets": [],
+        }))
+        .unwrap();
+        let at = |time: &str| time.parse::<DateTime<Utc>>().unwrap();
+
+        assert_eq!(
+            still_settling(&config, &release, at("2024-01-01T12:29:59Z")),
+            Some((29, 30))
+        );
+        assert_eq!(
+            still_settling(&config, &release, at("2024-01-01T12:30:00Z")),
+            None
+        );
+
+        config.min_release_age_minutes = None;
+        assert_eq!(
+            still_settling(&config, &release, at("2024-01-01T12:00:00Z")),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn dry_run_downloads_and_records_nothing() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&server, dir.path(), "");
+        Mock::given(method("GET"))
+            .and(path("/repos/owner/app/releases/latest"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(release_json(&server, "v1.0.0", "app.apk")),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(path("/download/app.apk"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let cli = Cli::parse_from(["tele-bot", "--dry-run"]);
+        let store = run_once(&cli, &config).await;
+
+        assert_eq!(store.get("owner/app").unwrap(), None);
+        assert!(!dir.path().join("prev.json").exists());
+    }
+
+    // Serves the asset and requests a shutdown, as if SIGTERM arrived while
+    // the first repo was downloading
+    struct ShutdownDuringDownload(Arc<AtomicBool>);
+
+    impl Respond for ShutdownDuringDownload {
+        fn respond(&self, _: &Request) -> ResponseTemplate {
+            self.0.store(true, Ordering::SeqCst);
+            ResponseTemplate::new(200).set_body_string("hello")
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_saves_state_of_finished_repos() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(&server, dir.path(), "");
+        config.repos.push(RepoConfig::new("owner/tool".to_string()));
+        for (repo, asset) in [("app", "app.apk"), ("tool", "tool.apk")] {
+            Mock::given(path(format!("/repos/owner/{}/releases/latest", repo)))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_json(release_json(&server, "v1.0.0", asset)),
+                )
+                .mount(&server)
+                .await;
+        }
+        let shutdown = Arc::new(AtomicBool::new(false));
+        Mock::given(path("/download/app.apk"))
+            .respond_with(ShutdownDuringDownload(shutdown.clone()))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/download/tool.apk"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let cli = Cli::parse_from(["tele-bot"]);
+        let state = config.state.path.clone().unwrap();
+        let mut store = JsonStore::open(&state, false).unwrap();
+        let outcome = process_repos(
+            &Client::new(),
+            &cli,
+            &config,
+            &config.repos,
+            &mut store,
+            None,
+            &shutdown,
+        )
+        .await
+        .unwrap();
+
+        assert!(outcome.interrupted);
+        let saved = JsonStore::open(&state, false).unwrap();
+        assert_eq!(saved.get("owner/app").unwrap().as_deref(), Some("v1.0.0"));
+        assert_eq!(saved.get("owner/tool").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn configured_proxy_is_used_except_for_no_proxy_hosts() {
+        let proxy = MockServer::start().await;
+        let direct = MockServer::start().await;
+        Mock::given(path("/app.apk"))
+            .and(header("host", "releases.example.invalid"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("proxied"))
+            .expect(1)
+            .mount(&proxy)
+            .await;
+        Mock::given(path("/app.apk"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("direct"))
+            .expect(1)
+            .mount(&direct)
+            .await;
+
+        let config: Config = toml::from_str(&format!(
+            "proxy = \"{}\"\nno_proxy = \"127.0.0.1\"",
+            proxy.uri()
+        ))
+        .unwrap();
+        let client = build_client(&config).unwrap();
+        let get = |url: String| {
+            let client = client.clone();
+            async move { client.get(url).send().await.unwrap().text().await.unwrap() }
+        };
+        assert_eq!(
+            get("http://releases.example.invalid/app.apk".into()).await,
+            "proxied"
+        );
+        assert_eq!(get(format!("{}/app.apk", direct.uri())).await, "direct");
+    }
+
+    #[tokio::test]
+    async fn client_sends_the_configured_user_agent() {
+        let server = MockServer::start().await;
+        let default_agent = format!("tele-bot/{}", env!("CARGO_PKG_VERSION"));
+        for (agent, extra) in [
+            (default_agent.as_str(), String::new()),
+            (
+                "my-mirror-bot/2.0",
+                "user_agent = \"my-mirror-bot/2.0\"".to_string(),
+            ),
+        ] {
+            Mock::given(path("/agent"))
+                .and(header("user-agent", agent))
+                .respond_with(ResponseTemplate::new(204))
+                .expect(1)
+                .mount(&server)
+                .await;
+            let config: Config = toml::from_str(&extra).unwrap();
+            let client = build_client(&config).unwrap();
+            let resp = client
+                .get(format!("{}/agent", server.uri()))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), 204, "{}", agent);
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_releases_across_repos_download_once() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(&server, dir.path(), "dedupe_releases = true");
+        config.repos.push(RepoConfig::new("mirror/app".to_string()));
+        let mut release = release_json(&server, "v1.0.0", "app.apk");
+        release["assets"][0]["digest"] = json!("sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e");
+        for repo in ["owner/app", "mirror/app"] {
+            Mock::given(path(format!("/repos/{}/releases/latest", repo)))
+                .respond_with(ResponseTemplate::new(200).set_body_json(&release))
+                .mount(&server)
+                .await;
         }
-        let url = asset.browser_download_url.clone();
-        let filename = asset.name.clone();
-        let path = format!("{}/{}", dir, filename);
-        file_paths.push(path.clone());
+        Mock::given(path("/download/app.apk"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .expect(1)
+            .mount(&server)
+            .await;
 
-        let client = client.clone();
-        let dir = dir.clone();
+        let cli = Cli::parse_from(["tele-bot"]);
+        let store = run_once(&cli, &config).await;
 
-        tasks.push(tokio::spawn(async move {
-            download_asset(&client, &url, &dir, &filename).await
-        }));
+        assert_eq!(store.get("owner/app").unwrap().as_deref(), Some("v1.0.0"));
+        assert_eq!(store.get("mirror/app").unwrap().as_deref(), Some("v1.0.0"));
     }
 
-    // Wait for all downloads
-    for t in tasks {
-        t.await??;
+    #[tokio::test]
+    async fn incomplete_assets_wait_for_the_next_poll() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&server, dir.path(), "defer_incomplete_assets = true");
+        let mut uploading = release_json(&server, "v1.0.0", "app.apk");
+        uploading["assets"][0]["size"] = json!(0);
+        uploading["assets"][0]["state"] = json!("open");
+        Mock::given(path("/repos/owner/app/releases/latest"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(uploading))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/repos/owner/app/releases/latest"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(release_json(&server, "v1.0.0", "app.apk")),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(path("/download/app.apk"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let cli = Cli::parse_from(["tele-bot"]);
+        let store = run_once(&cli, &config).await;
+        assert_eq!(store.get("owner/app").unwrap(), None);
+
+        let store = run_once(&cli, &config).await;
+        assert_eq!(store.get("owner/app").unwrap().as_deref(), Some("v1.0.0"));
+        let asset = dir.path().join("assets/owner_app/app.apk");
+        assert_eq!(std::fs::read_to_string(asset).unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn dry_run_reports_the_state_diff_without_writing() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&server, dir.path(), "");
+        Mock::given(path("/repos/owner/app/releases/latest"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(release_json(&server, "v1.1.0", "app.apk")),
+            )
+            .mount(&server)
+            .await;
+        let state = config.state.path.clone().unwrap();
+        let v1 = r#"{"repos":{"owner/app":"v1.0.0"}}"#;
+        std::fs::write(&state, v1).unwrap();
+
+        let client = Client::new();
+        let cli = Cli::parse_from(["tele-bot", "--dry-run", "--show-state-diff"]);
+        run(&client, &cli, &config).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&state).unwrap(), v1);
+        assert!(!Path::new(&format!("{}.bak", state)).exists());
+        assert!(!Path::new(&format!("{}.lock", state)).exists());
+
+        let mut store = JsonStore::open(&state, false).unwrap();
+        let shutdown = AtomicBool::new(false);
+        let outcome = process_repos(
+            &client,
+            &cli,
+            &config,
+            &config.repos,
+            &mut store,
+            None,
+            &shutdown,
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome.state_diff, ["owner/app: v1.0.0 -> v1.1.0"]);
     }
 
-    // Write the file list
-    let mut list_file = fs::File::create("assets/list.txt")?;
-    for path in &file_paths {
-        list_file.write_all(path.as_bytes())?;
-        list_file.write_all(b"\n")?;
+    #[tokio::test]
+    async fn disabled_repos_are_not_fetched_or_recorded() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(&server, dir.path(), "");
+        config.repos[0].enabled = false;
+        Mock::given(path("/repos/owner/app/releases/latest"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(release_json(&server, "v1.0.0", "app.apk")),
+            )
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let cli = Cli::parse_from(["tele-bot"]);
+        let store = run_once(&cli, &config).await;
+        assert_eq!(store.get("owner/app").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn redirected_repo_moves_its_state_to_the_new_name() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&server, dir.path(), "follow_renames = true");
+        Mock::given(path("/repos/owner/app/releases/latest"))
+            .respond_with(
+                ResponseTemplate::new(301)
+                    .insert_header("Location", "/repos/owner/renamed/releases/latest"),
+            )
+            .mount(&server)
+            .await;
+        let mut release = release_json(&server, "v1.0.0", "app.apk");
+        release["html_url"] = json!(format!(
+            "{}/owner/renamed/releases/tag/v1.0.0",
+            server.uri()
+        ));
+        Mock::given(path("/repos/owner/renamed/releases/latest"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(release))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/download/app.apk"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .mount(&server)
+            .await;
+        let state = config.state.path.clone().unwrap();
+        let mut store = JsonStore::open(&state, false).unwrap();
+        store.set("owner/app", "v0.9.0").unwrap();
+        store.flush().unwrap();
+
+        let cli = Cli::parse_from(["tele-bot"]);
+        let store = run_once(&cli, &config).await;
+
+        assert_eq!(store.get("owner/app").unwrap(), None);
+        assert_eq!(
+            store.get("owner/renamed").unwrap().as_deref(),
+            Some("v1.0.0")
+        );
+        let asset = dir.path().join("assets/owner_renamed/app.apk");
+        assert_eq!(std::fs::read_to_string(asset).unwrap(), "hello");
     }
 
-    Ok(file_paths)
+    #[tokio::test]
+    async fn repo_token_overrides_the_global_token() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(&server, dir.path(), "github_token = \"global-token\"");
+        config
+            .repos
+            .push(RepoConfig::new("owner/private".to_string()));
+        config.repos[1].token = Some("repo-token".to_string());
+        for (repo, token) in [("app", "global-token"), ("private", "repo-token")] {
+            Mock::given(path(format!("/repos/owner/{}/releases/latest", repo)))
+                .and(header(
+                    "authorization",
+                    format!("Bearer {}", token).as_str(),
+                ))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_json(release_json(&server, "v1.0.0", "app.apk")),
+                )
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+        Mock::given(path("/download/app.apk"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .mount(&server)
+            .await;
+
+        let cli = Cli::parse_from(["tele-bot"]);
+        let store = run_once(&cli, &config).await;
+        assert_eq!(
+            store.get("owner/private").unwrap().as_deref(),
+            Some("v1.0.0")
+        );
+    }
+
+    #[tokio::test]
+    async fn client_builds_with_pool_settings() {
+        let server = MockServer::start().await;
+        Mock::given(path("/ping"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(2)
+            .mount(&server)
+            .await;
+        let config: Config =
+            toml::from_str("pool_max_idle_per_host = 1\npool_idle_timeout_secs = 5").unwrap();
+
+        let client = build_client(&config).unwrap();
+        for _ in 0..2 {
+            let resp = client
+                .get(format!("{}/ping", server.uri()))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), 204);
+        }
+    }
+
+    #[tokio::test]
+    async fn api_asset_url_downloads_with_the_token() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(&server, dir.path(), "github_token = \"secret\"");
+        config.repos[0].use_api_asset_url = true;
+        Mock::given(path("/repos/owner/app/releases/latest"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(release_json(&server, "v1.0.0", "app.apk")),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(path("/repos/owner/app/releases/assets/1"))
+            .and(header("authorization", "Bearer secret"))
+            .and(header("accept", "application/octet-stream"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/download/app.apk"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let cli = Cli::parse_from(["tele-bot"]);
+        run_once(&cli, &config).await;
+        let asset = dir.path().join("assets/owner_app/app.apk");
+        assert_eq!(std::fs::read_to_string(asset).unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn notify_all_new_picks_up_backports_behind_an_unchanged_latest() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(&server, dir.path(), "");
+        config.repos[0].notify_all_new = true;
+        let latest = release_json(&server, "v2.0.0", "app-v2.0.0.apk");
+        let mut backport = release_json(&server, "v1.9.1", "app-v1.9.1.apk");
+        backport["published_at"] = json!("2024-02-01T00:00:00Z");
+        Mock::given(path("/repos/owner/app/releases/latest"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&latest))
+            .mount(&server)
+            .await;
+        Mock::given(path("/repos/owner/app/releases"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([backport, latest])))
+            .mount(&server)
+            .await;
+        Mock::given(path("/download/app-v1.9.1.apk"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/download/app-v2.0.0.apk"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+        let state = config.state.path.clone().unwrap();
+        let mut store = JsonStore::open(&state, false).unwrap();
+        store.set("owner/app", "v2.0.0").unwrap();
+        store.flush().unwrap();
+
+        let cli = Cli::parse_from(["tele-bot"]);
+        let store = run_once(&cli, &config).await;
+        assert_eq!(store.get("owner/app").unwrap().as_deref(), Some("v1.9.1"));
+
+        // The backport is now the newest recorded release and latest is
+        // older than it, so the next run has nothing to do
+        let store = run_once(&cli, &config).await;
+        assert_eq!(store.get("owner/app").unwrap().as_deref(), Some("v1.9.1"));
+        let list = std::fs::read_to_string(dir.path().join("assets/list.txt")).unwrap();
+        assert!(list.is_empty());
+    }
+
+    #[tokio::test]
+    async fn list_assets_prints_an_aligned_table() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&server, dir.path(), "");
+        Mock::given(path("/repos/owner/tool/releases/latest"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "tag_name": "v2.1.0",
+                "assets": [
+                    {
+                        "name": "tool-arm64-v8a.apk",
+                        "browser_download_url": "",
+                        "size": 10485760,
+                        "content_type": "application/vnd.android.package-archive",
+                    },
+                    {
+                        "name": "checksums.txt",
+                        "browser_download_url": "",
+                        "size": 512,
+                        "content_type": "text/plain",
+                    },
+                ],
+            })))
+            .mount(&server)
+            .await;
+
+        let table = list_assets(&Client::new(), &config, "https://github.com/owner/tool")
+            .await
+            .unwrap();
+        assert_eq!(
+            table,
+            "owner/tool v2.1.0\n\
+             tool-arm64-v8a.apk      10485760  application/vnd.android.package-archive\n\
+             checksums.txt                512  text/plain\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn healthcheck_pings_success_or_fail_url() {
+        let server = MockServer::start().await;
+        Mock::given(path("/ping/abc"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/ping/abc/fail"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = Client::new();
+        let url = format!("{}/ping/abc", server.uri());
+        ping_healthcheck(&client, &url, true).await;
+        ping_healthcheck(&client, &format!("{}/", url), false).await;
+    }
+
+    #[tokio::test]
+    async fn require_asset_name_waits_for_the_asset() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(&server, dir.path(), "");
+        config.repos[0].require_asset_name = Some("app-release.apk".to_string());
+        Mock::given(path("/repos/owner/app/releases/latest"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(release_json(
+                &server,
+                "v1.0.0",
+                "checksums.txt",
+            )))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/repos/owner/app/releases/latest"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(release_json(
+                &server,
+                "v1.0.0",
+                "app-release.apk",
+            )))
+            .mount(&server)
+            .await;
+        Mock::given(path("/download/app-release.apk"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let cli = Cli::parse_from(["tele-bot"]);
+        let store = run_once(&cli, &config).await;
+        assert_eq!(store.get("owner/app").unwrap(), None);
+
+        let store = run_once(&cli, &config).await;
+        assert_eq!(store.get("owner/app").unwrap().as_deref(), Some("v1.0.0"));
+    }
 }
@@ -0,0 +1,529 @@
+use crate::config::{StateBackend, StateConfig};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, TryLockError};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+// Last seen tag per repo, behind whichever backend the config selects.
+pub trait StateStore {
+    fn get(&self, repo: &str) -> anyhow::Result<Option<String>>;
+    fn set(&mut self, repo: &str, tag: &str) -> anyhow::Result<()>;
+    fn remove(&mut self, repo: &str) -> anyhow::Result<()>;
+    fn flush(&mut self) -> anyhow::Result<()>;
+    // Write a copy into dir, for when flush keeps failing. None if the
+    // backend keeps nothing in memory that could be lost.
+    fn save_copy(&self, _dir: &Path) -> anyhow::Result<Option<PathBuf>> {
+        Ok(None)
+    }
+}
+
+pub fn state_path(config: &StateConfig) -> &str {
+    match (&config.path, &config.backend) {
+        (Some(path), _) => path,
+        (None, StateBackend::Json) => "prev.json",
+        (None, StateBackend::Sqlite) => "prev.db",
+    }
+}
+
+pub fn open_store(config: &StateConfig) -> anyhow::Result<Box<dyn StateStore>> {
+    let path = state_path(config);
+    let store: Box<dyn StateStore> = match config.backend {
+        StateBackend::Json => Box::new(JsonStore::open(path, config.compact)?),
+        StateBackend::Sqlite => Box::new(SqliteStore::open(path)?),
+    };
+    Ok(store)
+}
+
+// Exclusive lock on <state>.lock so overlapping runs don't load the same
+// state and overwrite each other's updates. Held until the File is dropped.
+pub async fn lock_state(config: &StateConfig) -> anyhow::Result<File> {
+    let lock_path = format!("{}.lock", state_path(config));
+    let file = File::create(&lock_path)?;
+    match file.try_lock() {
+        Ok(()) => return Ok(file),
+        Err(TryLockError::WouldBlock) => {
+            info!("{} is held by another run, waiting.", lock_path);
+        }
+        Err(TryLockError::Error(err)) => return Err(err.into()),
+    }
+    let file = tokio::task::spawn_blocking(move || file.lock().map(|_| file)).await??;
+    Ok(file)
+}
+
+const STATE_VERSION: u32 = 2;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct PrevVersion {
+    version: u32,
+    repos: BTreeMap<String, RepoState>,
+}
+
+impl Default for PrevVersion {
+    fn default() -> Self {
+        PrevVersion {
+            version: STATE_VERSION,
+            repos: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct RepoState {
+    // last seen tag
+    tag: String,
+}
+
+// Unversioned prev.json from before per-repo state objects
+#[derive(Debug, Deserialize)]
+struct PrevVersionV1 {
+    repos: BTreeMap<String, String>, // repo -> last seen tag
+}
+
+fn is_gzip(path: &str) -> bool {
+    path.ends_with(".gz")
+}
+
+fn read_state_file(path: &str) -> std::io::Result<String> {
+    if !is_gzip(path) {
+        return std::fs::read_to_string(path);
+    }
+    let mut data = String::new();
+    GzDecoder::new(File::open(path)?).read_to_string(&mut data)?;
+    Ok(data)
+}
+
+// Written to <path>.tmp and renamed into place, so a failed write (disk
+// full) never leaves a truncated state file behind.
+fn write_state_file(path: &str, data: &str) -> std::io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let written = if is_gzip(path) {
+        let mut encoder = GzEncoder::new(File::create(&tmp)?, Compression::default());
+        encoder
+            .write_all(data.as_bytes())
+            .and_then(|()| encoder.finish())
+            .and_then(|file| file.sync_all())
+    } else {
+        File::create(&tmp).and_then(|mut file| {
+            file.write_all(data.as_bytes())?;
+            file.sync_all()
+        })
+    };
+    if let Err(err) = written.and_then(|()| std::fs::rename(&tmp, path)) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(err);
+    }
+    Ok(())
+}
+
+// The loaded state, and whether it was upgraded from v1
+fn load_prev(path: &str) -> anyhow::Result<(PrevVersion, bool)> {
+    if !Path::new(path).exists() {
+        return Ok((PrevVersion::default(), false));
+    }
+    let data = read_state_file(path).unwrap_or_default();
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(&data) else {
+        return Ok((PrevVersion::default(), false));
+    };
+
+    match value.get("version").and_then(|v| v.as_u64()) {
+        None => match serde_json::from_value::<PrevVersionV1>(value) {
+            Ok(v1) => Ok((migrate_v1(path, v1), true)),
+            Err(_) => Ok((PrevVersion::default(), false)),
+        },
+        Some(version) if version > STATE_VERSION as u64 => anyhow::bail!(
+            "{} has state version {}, this build only understands up to {}",
+            path,
+            version,
+            STATE_VERSION
+        ),
+        Some(_) => Ok((serde_json::from_value(value).unwrap_or_default(), false)),
+    }
+}
+
+// The upgraded state is only written on flush, so a dry run leaves the v1
+// file alone
+fn migrate_v1(path: &str, v1: PrevVersionV1) -> PrevVersion {
+    info!("Migrating {} to state version {}", path, STATE_VERSION);
+    let repos = v1
+        .repos
+        .into_iter()
+        .map(|(repo, tag)| (repo, RepoState { tag }))
+        .collect();
+    PrevVersion {
+        version: STATE_VERSION,
+        repos,
+    }
+}
+
+fn save_prev(path: &str, prev: &PrevVersion, compact: bool) -> anyhow::Result<()> {
+    let json = if compact {
+        serde_json::to_string(prev)?
+    } else {
+        serde_json::to_string_pretty(prev)?
+    };
+    if let Err(err) = write_state_file(path, &json) {
+        anyhow::bail!("could not write {}: {}", path, err);
+    }
+    Ok(())
+}
+
+const SAVE_ATTEMPTS: u32 = 3;
+const SAVE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Flush, retrying a couple of times. If the state still can't be written,
+// put a copy in fallback_dir so this run's progress can be restored by hand.
+pub async fn flush_state(store: &mut dyn StateStore, fallback_dir: &Path) -> anyhow::Result<()> {
+    let mut attempt = 1;
+    let err = loop {
+        match store.flush() {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt == SAVE_ATTEMPTS => break err,
+            Err(err) => {
+                warn!(
+                    "Saving state failed ({}), retrying ({}/{})",
+                    err, attempt, SAVE_ATTEMPTS
+                );
+                tokio::time::sleep(SAVE_RETRY_DELAY).await;
+                attempt += 1;
+            }
+        }
+    };
+
+    match store.save_copy(fallback_dir) {
+        Ok(Some(fallback)) => {
+            error!(
+                "Could not save state ({}), written to {} instead",
+                err,
+                fallback.display()
+            );
+            anyhow::bail!(
+                "could not save state: {} (saved to {})",
+                err,
+                fallback.display()
+            )
+        }
+        Ok(None) => Err(err),
+        Err(fallback_err) => {
+            error!(
+                "Could not save state ({}) or a copy in {} ({})",
+                err,
+                fallback_dir.display(),
+                fallback_err
+            );
+            Err(err)
+        }
+    }
+}
+
+// prev.json (gzipped if the path ends in .gz), read once and written back on flush
+pub struct JsonStore {
+    path: String,
+    prev: PrevVersion,
+    compact: bool,
+    // v1 file still on disk, copied to <path>.bak before it is overwritten
+    migrated: bool,
+}
+
+impl JsonStore {
+    pub fn open(path: &str, compact: bool) -> anyhow::Result<Self> {
+        let (prev, migrated) = load_prev(path)?;
+        Ok(JsonStore {
+            path: path.to_string(),
+            prev,
+            compact,
+            migrated,
+        })
+    }
+}
+
+impl StateStore for JsonStore {
+    fn get(&self, repo: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.prev.repos.get(repo).map(|state| state.tag.clone()))
+    }
+
+    fn set(&mut self, repo: &str, tag: &str) -> anyhow::Result<()> {
+        self.prev.repos.entry(repo.to_string()).or_default().tag = tag.to_string();
+        Ok(())
+    }
+
+    fn remove(&mut self, repo: &str) -> anyhow::Result<()> {
+        self.prev.repos.remove(repo);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if self.migrated {
+            let backup = format!("{}.bak", self.path);
+            std::fs::copy(&self.path, &backup)?;
+            info!("Original v1 state saved as {}", backup);
+            self.migrated = false;
+        }
+        save_prev(&self.path, &self.prev, self.compact)
+    }
+
+    fn save_copy(&self, dir: &Path) -> anyhow::Result<Option<PathBuf>> {
+        let name = Path::new(&self.path)
+            .file_name()
+            .unwrap_or("prev.json".as_ref());
+        let copy = dir.join(name);
+        save_prev(&copy.to_string_lossy(), &self.prev, self.compact)?;
+        Ok(Some(copy))
+    }
+}
+
+// Writes go straight to the database, so concurrent runs see each other's updates.
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(Duration::from_secs(30))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS releases (repo TEXT PRIMARY KEY, tag TEXT NOT NULL)",
+            [],
+        )?;
+        Ok(SqliteStore { conn })
+    }
+}
+
+impl StateStore for SqliteStore {
+    fn get(&self, repo: &str) -> anyhow::Result<Option<String>> {
+        let tag = self
+            .conn
+            .query_row(
+                "SELECT tag FROM releases WHERE repo = ?1",
+                params![repo],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(tag)
+    }
+
+    fn set(&mut self, repo: &str, tag: &str) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO releases (repo, tag) VALUES (?1, ?2)
+             ON CONFLICT(repo) DO UPDATE SET tag = excluded.tag",
+            params![repo, tag],
+        )?;
+        Ok(())
+    }
+
+    fn remove(&mut self, repo: &str) -> anyhow::Result<()> {
+        self.conn
+            .execute("DELETE FROM releases WHERE repo = ?1", params![repo])?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_write_keeps_the_previous_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("prev.json");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "{\"version\":2,\"repos\":{}}").unwrap();
+        // A directory in the way makes writing the temp file fail
+        std::fs::create_dir(format!("{}.tmp", path)).unwrap();
+
+        assert!(write_state_file(path, "new").is_err());
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            "{\"version\":2,\"repos\":{}}"
+        );
+    }
+
+    #[tokio::test]
+    async fn flush_state_falls_back_to_a_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let fallback = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing/prev.json");
+        let mut store = JsonStore::open(path.to_str().unwrap(), false).unwrap();
+        store.set("owner/app", "v1.0.0").unwrap();
+
+        let err = flush_state(&mut store, fallback.path()).await.unwrap_err();
+        let copy = fallback.path().join("prev.json");
+        assert!(err.to_string().contains(copy.to_str().unwrap()));
+        let saved = JsonStore::open(copy.to_str().unwrap(), false).unwrap();
+        assert_eq!(saved.get("owner/app").unwrap().as_deref(), Some("v1.0.0"));
+    }
+
+    fn store_at(dir: &Path, name: &str, compact: bool) -> (String, JsonStore) {
+        let path = dir.join(name).to_str().unwrap().to_string();
+        let store = JsonStore::open(&path, compact).unwrap();
+        (path, store)
+    }
+
+    #[test]
+    fn json_store_output_is_sorted_and_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut store) = store_at(dir.path(), "prev.json", false);
+        store.set("zeta/app", "v2").unwrap();
+        store.set("alpha/app", "v1").unwrap();
+        store.flush().unwrap();
+        let first = std::fs::read_to_string(&path).unwrap();
+        let expected = r#"{
+  "version": 2,
+  "repos": {
+    "alpha/app": {
+      "tag": "v1"
+    },
+    "zeta/app": {
+      "tag": "v2"
+    }
+  }
+}"#;
+        assert_eq!(first, expected);
+
+        let (_, mut reopened) = store_at(dir.path(), "prev.json", false);
+        assert_eq!(reopened.get("zeta/app").unwrap().as_deref(), Some("v2"));
+        reopened.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), first);
+    }
+
+    #[test]
+    fn sqlite_store_through_open_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StateConfig {
+            backend: StateBackend::Sqlite,
+            path: Some(dir.path().join("prev.db").to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        let mut store = open_store(&config).unwrap();
+        store.set("owner/app", "v1").unwrap();
+        store.set("owner/app", "v2").unwrap();
+        store.set("other/tool", "v1").unwrap();
+        store.remove("other/tool").unwrap();
+        store.flush().unwrap();
+        assert_eq!(store.save_copy(dir.path()).unwrap(), None);
+        drop(store);
+
+        let store = open_store(&config).unwrap();
+        assert_eq!(store.get("owner/app").unwrap().as_deref(), Some("v2"));
+        assert_eq!(store.get("other/tool").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn second_run_waits_for_the_state_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StateConfig {
+            path: Some(dir.path().join("prev.json").to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        let first = lock_state(&config).await.unwrap();
+
+        let config2 = config.clone();
+        let mut second = tokio::spawn(async move { lock_state(&config2).await });
+        let waited = tokio::time::timeout(Duration::from_millis(200), &mut second).await;
+        assert!(waited.is_err(), "second lock taken while the first is held");
+
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(5), second)
+            .await
+            .expect("second lock not taken after the first was dropped")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn gzipped_state_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut store) = store_at(dir.path(), "prev.json.gz", false);
+        store.set("owner/app", "v1.0.0").unwrap();
+        store.flush().unwrap();
+
+        let raw = std::fs::read(&path).unwrap();
+        assert_eq!(&raw[..2], &[0x1f, 0x8b]);
+        let (_, reopened) = store_at(dir.path(), "prev.json.gz", false);
+        assert_eq!(
+            reopened.get("owner/app").unwrap().as_deref(),
+            Some("v1.0.0")
+        );
+    }
+
+    #[test]
+    fn v1_state_is_migrated_with_a_backup_on_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let v1 = r#"{"repos":{"owner/app":"v1.0.0"}}"#;
+        std::fs::write(dir.path().join("prev.json"), v1).unwrap();
+
+        let (path, mut store) = store_at(dir.path(), "prev.json", true);
+        assert_eq!(store.get("owner/app").unwrap().as_deref(), Some("v1.0.0"));
+        let backup = format!("{}.bak", path);
+        assert!(!Path::new(&backup).exists());
+
+        store.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), v1);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            r#"{"version":2,"repos":{"owner/app":{"tag":"v1.0.0"}}}"#
+        );
+    }
+
+    #[test]
+    fn newer_state_versions_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("prev.json");
+        std::fs::write(&path, r#"{"version":3,"repos":{}}"#).unwrap();
+        assert!(JsonStore::open(path.to_str().unwrap(), false).is_err());
+    }
+
+    // CI commits prev.json when it changes, so an unchanged run must write
+    // it back byte for byte
+    #[test]
+    fn committed_state_is_v2_and_stable() {
+        let committed =
+            std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/prev.json")).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("prev.json"), &committed).unwrap();
+
+        let (path, mut store) = store_at(dir.path(), "prev.json", false);
+        assert!(!store.migrated);
+        store.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), committed);
+    }
+
+    #[test]
+    fn compact_state_is_one_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut store) = store_at(dir.path(), "prev.json", true);
+        store.set("owner/app", "v1.0.0").unwrap();
+        store.flush().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            r#"{"version":2,"repos":{"owner/app":{"tag":"v1.0.0"}}}"#
+        );
+
+        let (_, reopened) = store_at(dir.path(), "prev.json", false);
+        assert_eq!(
+            reopened.get("owner/app").unwrap().as_deref(),
+            Some("v1.0.0")
+        );
+    }
+
+    #[test]
+    fn json_store_removes_repos() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, mut store) = store_at(dir.path(), "prev.json", false);
+        store.set("owner/app", "v1").unwrap();
+        store.remove("owner/app").unwrap();
+        store.flush().unwrap();
+        let (_, reopened) = store_at(dir.path(), "prev.json", false);
+        assert_eq!(reopened.get("owner/app").unwrap(), None);
+    }
+}